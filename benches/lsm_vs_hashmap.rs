@@ -50,7 +50,7 @@ fn bench_put(c: &mut Criterion) {
                     let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 8 * 1024).unwrap();
                     (eng, gen_kv(n, 32, 1), dir)
                 },
-                |(mut eng, items, dir)| {
+                |(eng, items, dir)| {
                     for (k, v) in items.into_iter() {
                         eng.put(&k, &v).unwrap();
                     }
@@ -86,7 +86,7 @@ fn bench_get(c: &mut Criterion) {
             let dir = PathBuf::from("target/bench-tmp/lsm_get");
             let _ = fs::remove_dir_all(&dir);
             fs::create_dir_all(&dir).unwrap();
-            let mut eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 8 * 1024).unwrap();
+            let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 8 * 1024).unwrap();
             let items = gen_kv(n, 32, 2);
             for (k, v) in items.iter() {
                 eng.put(k, v).unwrap();
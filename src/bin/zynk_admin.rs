@@ -0,0 +1,83 @@
+use std::path::Path;
+use std::process::ExitCode;
+use zynk::storage::repair::{self, QUARANTINE_DIR};
+
+const USAGE: &str = "usage: zynk-admin verify <data_dir>\n       zynk-admin repair <data_dir>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mode, dir) = match args.as_slice() {
+        [mode, dir] => (mode.as_str(), Path::new(dir)),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let res = match mode {
+        "verify" => verify(dir),
+        "repair" => repair(dir),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match res {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("zynk-admin {mode}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints one line per table and per manifest problem; true if all is well.
+fn verify(dir: &Path) -> std::io::Result<bool> {
+    let report = repair::verify(dir)?;
+    for table in &report.tables {
+        match &table.result {
+            Ok(s) => println!(
+                "ok      {} entries={} deletions={} blocks={} seq={}..{}",
+                table.path.display(),
+                s.meta.num_entries,
+                s.meta.num_deletions,
+                s.num_blocks,
+                s.min_seq,
+                s.max_seq
+            ),
+            Err(e) => println!("CORRUPT {}: {e}", table.path.display()),
+        }
+    }
+    if let Some(e) = &report.manifest_error {
+        println!("CORRUPT manifest: {e}");
+    }
+    for id in &report.missing {
+        println!("MISSING table {id} referenced by the manifest");
+    }
+    let bad = report.tables.iter().filter(|t| t.result.is_err()).count();
+    println!(
+        "{} tables, {bad} corrupt, {} missing",
+        report.tables.len(),
+        report.missing.len()
+    );
+    Ok(report.is_ok())
+}
+
+fn repair(dir: &Path) -> std::io::Result<bool> {
+    let report = repair::repair(dir)?;
+    for path in &report.quarantined {
+        println!("moved {} to {QUARANTINE_DIR}/", path.display());
+    }
+    for id in &report.dropped {
+        println!("dropped table {id} from the manifest");
+    }
+    if report.rebuilt_levels {
+        println!("manifest was unreadable; levels rebuilt from sequence numbers");
+    }
+    println!(
+        "wrote {} with {} tables",
+        report.manifest,
+        report.tables.len()
+    );
+    Ok(true)
+}
@@ -4,8 +4,6 @@ use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderValue};
 use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
 use tonic::transport::Channel;
 use tonic::{Request, Response, Status};
 use zynk::engine::kv::LsmEngine;
@@ -21,22 +19,22 @@ use pb::{
 };
 
 struct KvSvc {
-    engine: Arc<RwLock<LsmEngine>>,
+    engine: LsmEngine,
 }
 
 #[tonic::async_trait]
 impl Kv for KvSvc {
     async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
         let req = request.into_inner();
-        let mut eng = self.engine.write().await;
-        eng.put(&req.key, &req.value).map_err(to_status)?;
+        let engine = self.engine.clone();
+        blocking(move || engine.put(&req.key, &req.value)).await?;
         Ok(Response::new(PutResponse {}))
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
         let req = request.into_inner();
-        let eng = self.engine.read().await;
-        match eng.get(&req.key).map_err(to_status)? {
+        let engine = self.engine.clone();
+        match blocking(move || engine.get(&req.key)).await? {
             Some(v) => Ok(Response::new(GetResponse {
                 value: v,
                 found: true,
@@ -50,8 +48,8 @@ impl Kv for KvSvc {
 
     async fn del(&self, request: Request<DelRequest>) -> Result<Response<DelResponse>, Status> {
         let req = request.into_inner();
-        let mut eng = self.engine.write().await;
-        eng.delete(&req.key).map_err(to_status)?;
+        let engine = self.engine.clone();
+        blocking(move || engine.delete(&req.key)).await?;
         Ok(Response::new(DelResponse { removed: true }))
     }
 }
@@ -74,6 +72,19 @@ fn to_status(e: std::io::Error) -> Status {
     Status::internal(e.to_string())
 }
 
+/// Runs an engine call on the blocking pool: writes can wait on the write
+/// lock or an fsync, which must not hold up a runtime worker.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(to_status)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let port: u16 = std::env::var("PORT")
@@ -96,7 +107,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let engine = LsmEngine::new_with_manifest_and_actor(&data_dir, 64 * 1024, 8 * 1024, actor_id)?;
     let svc = KvSvc {
-        engine: Arc::new(RwLock::new(engine)),
+        engine,
     };
     println!(
         "zynkd listening on {} (ACTOR_ID={}, DATA_DIR={})",
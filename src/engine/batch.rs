@@ -0,0 +1,122 @@
+use crate::engine::crdt::{ElementId, GSet, Rga, CRDT};
+
+/// Writes applied together by `LsmEngine::write`: all of them become visible
+/// at once, share one WAL record and land in the same memtable.
+///
+/// Operations apply in the order they were added, so a CRDT op sees the
+/// puts, deletes and other CRDT ops on its key earlier in the batch.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+    Update(Vec<u8>, CrdtOp),
+}
+
+/// A read-modify-write of a CRDT value, resolved against the key's current
+/// value under the engine's write lock.
+#[derive(Clone, Debug)]
+pub(crate) enum CrdtOp {
+    GSetAdd(Vec<u8>),
+    RgaInsertAfter {
+        id: ElementId,
+        prev: Option<ElementId>,
+        value: Vec<u8>,
+    },
+    RgaDelete(ElementId),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+    }
+
+    /// Deletes every key in `[start, end)`, including keys put earlier in
+    /// the batch, with a single range tombstone. An empty range is ignored.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        if start < end {
+            self.ops
+                .push(BatchOp::DeleteRange(start.to_vec(), end.to_vec()));
+        }
+    }
+
+    pub fn gset_add(&mut self, key: Vec<u8>, elem: Vec<u8>) {
+        self.ops.push(BatchOp::Update(key, CrdtOp::GSetAdd(elem)));
+    }
+
+    pub fn rga_insert_after(
+        &mut self,
+        key: &[u8],
+        prev: Option<ElementId>,
+        value: Vec<u8>,
+        actor_id: u64,
+        counter: u64,
+    ) {
+        let id = ElementId::new(actor_id, counter);
+        let op = CrdtOp::RgaInsertAfter { id, prev, value };
+        self.ops.push(BatchOp::Update(key.to_vec(), op));
+    }
+
+    pub fn rga_delete(&mut self, key: &[u8], id: ElementId) {
+        self.ops
+            .push(BatchOp::Update(key.to_vec(), CrdtOp::RgaDelete(id)));
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+impl CrdtOp {
+    /// New value of a key holding `old`, or `None` if nothing changes.
+    pub(crate) fn apply(self, old: Option<&[u8]>) -> Option<Vec<u8>> {
+        match self {
+            CrdtOp::GSetAdd(elem) => {
+                let mut gs = match old {
+                    Some(bytes) => GSet::from_bytes(bytes),
+                    None => GSet::new(),
+                };
+                gs.insert(elem);
+                Some(gs.to_bytes())
+            }
+            CrdtOp::RgaInsertAfter { id, prev, value } => {
+                let mut rga = match old {
+                    Some(bs) => Rga::from_bytes(bs),
+                    None => Rga::new(),
+                };
+                rga.insert(id, prev, value);
+                Some(rga.to_bytes())
+            }
+            CrdtOp::RgaDelete(id) => {
+                let mut rga = Rga::from_bytes(old?); // kuch nai hein delete karne ko
+                rga.delete(id);
+                Some(rga.to_bytes())
+            }
+        }
+    }
+}
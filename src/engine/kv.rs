@@ -1,20 +1,176 @@
+use crate::engine::batch::{BatchOp, WriteBatch};
 use crate::engine::crdt::{ElementId, Rga};
-use crate::storage::manifest::{fsync_dir, open_manifest_append, read_current_or_init, Manifest};
-use crate::storage::memtable::{flush_memtable_to_sstable, Entry, MemTable, MemTableSet};
-use crate::storage::sstable::{reader::SsTableReader, TableId};
+use crate::storage::compaction::{pick_compaction, CompactionOptions, CompactionTask, Compactor};
+use crate::storage::lock::DirLock;
+use crate::storage::manifest::{
+    fsync_dir, open_manifest_append, read_current_or_init, read_manifest_state,
+    remove_stale_manifests, Manifest, ManifestEdit, ManifestState,
+};
+use crate::storage::memtable::{
+    write_level0_table, Entry, FlushTask, Flusher, MemTable, MemTableIter, MemTableSet, SeqNo,
+};
+use crate::storage::merge::{prefix_end, EntryIter, MergingIter, ScanIter};
+use crate::storage::snapshot::{Snapshot, SnapshotList};
+use crate::storage::sstable::{
+    cache::{BlockCache, BlockCacheStats},
+    compression::CompressionType,
+    iter::SsTableIter,
+    range_del,
+    reader::SsTableReader,
+    table_id_from_name, table_path, Lookup, TableId, TableMeta, TableOptions,
+};
+use crate::storage::wal::{self, LoggedOp, WalSyncPolicy, WalWriter};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
+/// Tunables for an `LsmEngine`.
+#[derive(Clone, Debug)]
+pub struct EngineOptions {
+    pub memtable_max_bytes: usize,
+    /// Frozen memtables allowed to wait for the background flusher before
+    /// writes stall until one of them is installed.
+    pub max_immutable_memtables: usize,
+    pub block_bytes: usize,
+    pub bloom_bits_per_key: usize,
+    /// Codec for newly written blocks; existing tables keep theirs.
+    pub compression: CompressionType,
+    /// Capacity of the shared block cache in bytes; 0 disables it.
+    pub block_cache_bytes: usize,
+    pub wal_sync: WalSyncPolicy,
+    /// Size past which the manifest is checkpointed into a fresh file
+    /// holding just the live table set.
+    pub manifest_max_bytes: u64,
+    pub compaction: CompactionOptions,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            memtable_max_bytes: 64 * 1024,
+            max_immutable_memtables: 2,
+            block_bytes: 8 * 1024,
+            bloom_bits_per_key: 10,
+            compression: CompressionType::None,
+            block_cache_bytes: 8 * 1024 * 1024,
+            wal_sync: WalSyncPolicy::Always,
+            manifest_max_bytes: 1024 * 1024,
+            compaction: CompactionOptions::default(),
+        }
+    }
+}
+
+/// Size and contents of one level, summed from the manifest's table
+/// metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    pub tables: usize,
+    pub bytes: u64,
+    pub entries: u64,
+    pub deletions: u64,
+}
+
+/// Error payload of mutations on an engine opened with
+/// `LsmEngine::open_read_only`, surfaced as `ErrorKind::PermissionDenied`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadOnlyError;
+
+impl ReadOnlyError {
+    /// Whether `err` was caused by writing to a read-only engine.
+    pub fn is(err: &std::io::Error) -> bool {
+        err.get_ref().is_some_and(|e| e.is::<ReadOnlyError>())
+    }
+}
+
+impl std::fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("engine is open read-only")
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+/// What only a writable engine has: the files it appends to, the background
+/// workers and the data directory lock. Held behind a mutex that every
+/// mutation takes, so writes are applied one at a time in sequence order.
+struct Writer {
+    manifest: Manifest,
+    wal: WalWriter,
+    compactor: Compactor,
+    flusher: Flusher,
+    table_opts: TableOptions,
+    /// Set when a background flush fails. Later flushes are not installed
+    /// and writes are refused, so frozen memtables keep shadowing tables in
+    /// order; reopening the engine recovers them from their WAL segments.
+    bg_error: Option<(std::io::ErrorKind, String)>,
+    /// Held until the workers above have been joined.
+    _lock: DirLock,
+}
+
+/// What reads see: the memtables and live tables at one point in time.
+///
+/// A version is never modified once published. Readers clone the current
+/// one and search it without holding any lock; the writer publishes a new
+/// version whenever a memtable is rotated or tables are installed. Writes
+/// still reach readers in between, through the shared active memtable.
+#[derive(Clone)]
+struct Version {
+    memtables: MemTableSet,
+    /// Live tables in lookup order: level 0 newest first, then each deeper
+    /// level by smallest key.
+    sstables: Vec<(TableMeta, PathBuf, Arc<SsTableReader>)>,
+    db_id: u128,
+}
+
+impl Version {
+    fn sort_tables(&mut self) {
+        self.sstables.sort_by(|(a, _, _), (b, _, _)| {
+            a.level.cmp(&b.level).then_with(|| {
+                if a.level == 0 {
+                    b.id.cmp(&a.id)
+                } else {
+                    a.smallest.cmp(&b.smallest)
+                }
+            })
+        });
+    }
+
+    fn table_metas(&self) -> Vec<TableMeta> {
+        self.sstables
+            .iter()
+            .map(|(meta, _, _)| meta.clone())
+            .collect()
+    }
+}
+
+/// A handle to an open engine. Handles are cheap to clone and can be used
+/// from any number of threads: reads never wait for writes, flushes or
+/// compactions, and writes are serialised internally.
+#[derive(Clone)]
 pub struct LsmEngine {
+    inner: Arc<EngineInner>,
+}
+
+struct EngineInner {
     data_dir: PathBuf,
-    memtables: MemTableSet,
-    sstables: Vec<(TableId, PathBuf, SsTableReader)>,
-    block_bytes: usize,
-    pub actor_id: u64,
+    actor_id: u64,
     local_counter: AtomicU64,
-    next_table_id: TableId,
-    manifest: Manifest,
+    next_table_id: Arc<AtomicU64>,
+    manifest_max_bytes: u64,
+    wal_sync: WalSyncPolicy,
+    compaction: CompactionOptions,
+    max_immutables: usize,
+    /// Sequence number of the most recent write. Published once the write
+    /// is in the memtable, so a snapshot at this number sees all of it.
+    last_seq: AtomicU64,
+    snapshots: SnapshotList,
+    block_cache: BlockCache,
+    /// Leftover files deleted from `sst/` when the engine was opened.
+    removed_orphans: Vec<PathBuf>,
+    version: RwLock<Arc<Version>>,
+    /// `None` for engines opened read-only.
+    writer: Option<Mutex<Writer>>,
 }
 
 impl LsmEngine {
@@ -26,17 +182,55 @@ impl LsmEngine {
         let data_dir = data_dir.as_ref().to_path_buf();
         let sst_dir = data_dir.join("sst");
         fs::create_dir_all(&sst_dir)?;
-        let memtables = MemTableSet::with_capacity(memtable_max_bytes);
+        fs::create_dir_all(wal::wal_dir(&data_dir))?;
+        let lock = DirLock::acquire(&data_dir)?;
         let manifest = Manifest::new(data_dir.join("MANIFEST-000001"))?;
-        Ok(Self {
-            data_dir,
-            memtables,
-            sstables: Vec::new(),
+        let wal_sync = WalSyncPolicy::default();
+        let wal_id = wal::list_wal_ids(&data_dir)?.last().copied().unwrap_or(0) + 1;
+        let wal = WalWriter::create(wal::wal_path(&data_dir, wal_id), wal_id, wal_sync)?;
+        let compaction = CompactionOptions::default();
+        let table_opts = TableOptions {
             block_bytes,
-            actor_id: 0,
-            local_counter: AtomicU64::new(0),
-            next_table_id: 1,
-            manifest,
+            ..TableOptions::default()
+        };
+        let next_table_id = Arc::new(AtomicU64::new(1));
+        let flusher = Flusher::spawn(sst_dir.clone(), table_opts.clone(), next_table_id.clone());
+        let compactor = Compactor::spawn(
+            sst_dir,
+            table_opts.clone(),
+            compaction.target_table_bytes,
+            next_table_id.clone(),
+        );
+        let version = Version {
+            memtables: MemTableSet::with_capacity(memtable_max_bytes),
+            sstables: Vec::new(),
+            db_id: 0,
+        };
+        Ok(Self {
+            inner: Arc::new(EngineInner {
+                data_dir,
+                actor_id: 0,
+                local_counter: AtomicU64::new(0),
+                next_table_id,
+                manifest_max_bytes: EngineOptions::default().manifest_max_bytes,
+                wal_sync,
+                compaction,
+                max_immutables: EngineOptions::default().max_immutable_memtables,
+                last_seq: AtomicU64::new(0),
+                snapshots: SnapshotList::new(),
+                block_cache: BlockCache::new(EngineOptions::default().block_cache_bytes),
+                removed_orphans: Vec::new(),
+                version: RwLock::new(Arc::new(version)),
+                writer: Some(Mutex::new(Writer {
+                    manifest,
+                    wal,
+                    compactor,
+                    flusher,
+                    table_opts,
+                    bg_error: None,
+                    _lock: lock,
+                })),
+            }),
         })
     }
 
@@ -45,35 +239,348 @@ impl LsmEngine {
         memtable_max_bytes: usize,
         block_bytes: usize,
     ) -> std::io::Result<Self> {
+        Self::open(
+            data_dir,
+            EngineOptions {
+                memtable_max_bytes,
+                block_bytes,
+                ..EngineOptions::default()
+            },
+        )
+    }
+
+    /// Opens the engine, replaying the manifest and any WAL segments left
+    /// behind by a previous process. Fails with `ErrorKind::WouldBlock` while
+    /// another engine holds the data directory's `LOCK`.
+    pub fn open<P: AsRef<Path>>(data_dir: P, opts: EngineOptions) -> std::io::Result<Self> {
         let data_dir = data_dir.as_ref().to_path_buf();
         let sst_dir = data_dir.join("sst");
         fs::create_dir_all(&sst_dir)?;
+        fs::create_dir_all(wal::wal_dir(&data_dir))?;
+        let lock = DirLock::acquire(&data_dir)?;
 
-        let memtables = MemTableSet::with_capacity(memtable_max_bytes);
         let name = read_current_or_init(&data_dir, "MANIFEST-000001")?;
+        remove_stale_manifests(&data_dir, &name)?;
         let mut manifest = open_manifest_append(&data_dir, &name)?;
-        let active_tables_ids = manifest.replay_manifest()?;
+        let state = manifest.replay_manifest()?;
+        let active_tables = state.tables;
+        let db_id = match state.db_id {
+            Some(id) => id,
+            None => {
+                let id = rand::random::<u128>().max(1);
+                manifest.record(ManifestEdit {
+                    db_id: Some(id),
+                    ..ManifestEdit::default()
+                })?;
+                id
+            }
+        };
 
+        let block_cache = BlockCache::new(opts.block_cache_bytes);
         let mut sstables = Vec::new();
-        for id in active_tables_ids.clone() {
-            let path = data_dir.join("sst").join(format!("{id:06}.sst"));
-            if let Ok(reader) = SsTableReader::open(&path) {
-                sstables.push((id, path, reader));
+        let mut described = Vec::new();
+        let mut problems = Vec::new();
+        for meta in &active_tables {
+            let name = table_path(&sst_dir, meta.id);
+            match open_table(&sst_dir, meta, db_id, &block_cache) {
+                Ok(Some(table)) => {
+                    if meta.file_len == 0 {
+                        described.push(table.0.clone());
+                    }
+                    sstables.push(table);
+                }
+                Ok(None) => problems.push(format!("{} is missing", name.display())),
+                Err(e) => problems.push(format!("{} is unreadable: {e}", name.display())),
             }
         }
+        if !problems.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} references tables it cannot open: {}",
+                    manifest.file_name(),
+                    problems.join("; ")
+                ),
+            ));
+        }
+        // Tables missing from a truncated manifest may be exactly the ones
+        // its lost edits added, so they are left where they are.
+        let removed_orphans =
+            remove_orphan_files(&data_dir, &active_tables, !manifest.truncated())?;
+        if !described.is_empty() {
+            // Persist what older manifests did not record, once.
+            manifest.record(ManifestEdit {
+                removed: described.iter().map(|m| m.id).collect(),
+                added: described,
+                ..ManifestEdit::default()
+            })?;
+        }
 
-        let next_table_id = active_tables_ids.iter().copied().max().unwrap_or(0) + 1;
+        // Tables kept after a truncated manifest must not be overwritten by
+        // the next flush or compaction, so ids continue past every file.
+        let next_table_id = active_tables
+            .iter()
+            .map(|m| m.id)
+            .max()
+            .unwrap_or(0)
+            .max(max_table_id_in(&sst_dir)?)
+            + 1;
+        let next_table_id = Arc::new(AtomicU64::new(next_table_id));
+        let table_opts = TableOptions {
+            block_bytes: opts.block_bytes,
+            bloom_bits_per_key: opts.bloom_bits_per_key,
+            compression: opts.compression,
+            db_id,
+            ..TableOptions::default()
+        };
+        let flusher = Flusher::spawn(sst_dir.clone(), table_opts.clone(), next_table_id.clone());
+        let compactor = Compactor::spawn(
+            sst_dir,
+            table_opts.clone(),
+            opts.compaction.target_table_bytes,
+            next_table_id.clone(),
+        );
 
-        Ok(Self {
-            data_dir,
+        let wal_ids = wal::list_wal_ids(&data_dir)?;
+        if let Some(&newest) = wal_ids.last() {
+            // It stops being the newest segment once the next one exists.
+            wal::trim_torn_tail(&wal::wal_path(&data_dir, newest))?;
+        }
+        let wal_id = wal_ids.last().copied().unwrap_or(0) + 1;
+        let wal = WalWriter::create(wal::wal_path(&data_dir, wal_id), wal_id, opts.wal_sync)?;
+
+        let mut version = Version {
+            memtables: MemTableSet::with_capacity(opts.memtable_max_bytes),
+            sstables,
+            db_id,
+        };
+        version.sort_tables();
+        let eng = Self {
+            inner: Arc::new(EngineInner {
+                data_dir,
+                actor_id: 0,
+                local_counter: AtomicU64::new(0),
+                next_table_id,
+                manifest_max_bytes: opts.manifest_max_bytes,
+                wal_sync: opts.wal_sync,
+                compaction: opts.compaction,
+                max_immutables: opts.max_immutable_memtables.max(1),
+                last_seq: AtomicU64::new(state.last_seq),
+                snapshots: SnapshotList::new(),
+                block_cache,
+                removed_orphans,
+                version: RwLock::new(Arc::new(version)),
+                writer: Some(Mutex::new(Writer {
+                    manifest,
+                    wal,
+                    compactor,
+                    flusher,
+                    table_opts,
+                    bg_error: None,
+                    _lock: lock,
+                })),
+            }),
+        };
+        {
+            let mut w = eng.writer()?;
+            eng.recover_wals(&mut w, &wal_ids)?;
+            eng.maybe_checkpoint_manifest(&mut w)?;
+            eng.maybe_schedule_compaction(&mut w);
+        }
+        Ok(eng)
+    }
+
+    /// Opens the engine without taking the data directory lock or writing
+    /// anything, so tools and followers can inspect a live node's data. It
+    /// sees the tables in the manifest plus the writes still in WAL
+    /// segments, as of now; `refresh` catches up with the writer later.
+    /// Mutations fail with a `ReadOnlyError`.
+    pub fn open_read_only<P: AsRef<Path>>(
+        data_dir: P,
+        opts: EngineOptions,
+    ) -> std::io::Result<Self> {
+        let version = Version {
+            memtables: MemTableSet::with_capacity(opts.memtable_max_bytes),
+            sstables: Vec::new(),
+            db_id: 0,
+        };
+        let eng = Self {
+            inner: Arc::new(EngineInner {
+                data_dir: data_dir.as_ref().to_path_buf(),
+                actor_id: 0,
+                local_counter: AtomicU64::new(0),
+                next_table_id: Arc::new(AtomicU64::new(0)),
+                manifest_max_bytes: opts.manifest_max_bytes,
+                wal_sync: opts.wal_sync,
+                compaction: opts.compaction,
+                max_immutables: opts.max_immutable_memtables.max(1),
+                last_seq: AtomicU64::new(0),
+                snapshots: SnapshotList::new(),
+                block_cache: BlockCache::new(opts.block_cache_bytes),
+                removed_orphans: Vec::new(),
+                version: RwLock::new(Arc::new(version)),
+                writer: None,
+            }),
+        };
+        eng.refresh()?;
+        Ok(eng)
+    }
+
+    /// Temporary files and unreferenced tables that `open` deleted from
+    /// `sst/`, left behind by flushes or compactions a crash interrupted.
+    pub fn removed_orphans(&self) -> &[PathBuf] {
+        &self.inner.removed_orphans
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.inner.writer.is_none()
+    }
+
+    /// Catches a read-only engine up with the writer: switches to the table
+    /// set its manifest records, keeping the readers of unchanged tables,
+    /// and replays the WAL segments that hold the writes since. Snapshots
+    /// taken earlier may lose versions the writer has since compacted away.
+    /// A writable engine is always current, so this does nothing there.
+    ///
+    /// If the writer flushes, checkpoints its manifest or deletes a table
+    /// the manifest still named while this runs, the manifest is read
+    /// again; after a few tries the refresh fails and the engine keeps its
+    /// previous view.
+    pub fn refresh(&self) -> std::io::Result<()> {
+        self.refresh_with(read_manifest_state)
+    }
+
+    /// `refresh`, reading the writer's manifest with `read_state`.
+    fn refresh_with(
+        &self,
+        read_state: impl Fn(&Path) -> std::io::Result<ManifestState>,
+    ) -> std::io::Result<()> {
+        if !self.is_read_only() {
+            return Ok(());
+        }
+        let mut reason = String::new();
+        for _ in 0..REFRESH_ATTEMPTS {
+            match self.read_view(&read_state)? {
+                Ok((version, last_seq)) => {
+                    let inner = &*self.inner;
+                    let old =
+                        std::mem::replace(&mut *inner.version.write().unwrap(), Arc::new(version));
+                    let live = self.current();
+                    for (meta, _, _) in &old.sstables {
+                        if !live.sstables.iter().any(|t| t.0.id == meta.id) {
+                            inner.block_cache.evict_table(meta.id);
+                        }
+                    }
+                    inner.last_seq.fetch_max(last_seq, Ordering::SeqCst);
+                    return Ok(());
+                }
+                Err(why) => reason = why,
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            format!("refresh gave up after {REFRESH_ATTEMPTS} tries: {reason}"),
+        ))
+    }
+
+    /// Reads the manifest and then the WAL segments holding everything
+    /// newer, so memtables never shadow a newer flushed version. Returns
+    /// why to retry, instead, if a listed table is gone or a flush was
+    /// recorded while the segments were read, which may have deleted one.
+    /// A manifest that vanished after CURRENT named it was checkpointed
+    /// away, which is retried too.
+    fn read_view(
+        &self,
+        read_state: &impl Fn(&Path) -> std::io::Result<ManifestState>,
+    ) -> std::io::Result<Result<(Version, SeqNo), String>> {
+        let inner = &*self.inner;
+        let read = || match read_state(&inner.data_dir) {
+            Ok(state) => Ok(Ok(state)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Err(format!("the manifest was replaced: {e}")))
+            }
+            Err(e) => Err(e),
+        };
+        let state = match read()? {
+            Ok(state) => state,
+            Err(why) => return Ok(Err(why)),
+        };
+        let db_id = state.db_id.unwrap_or(0);
+        let sst_dir = inner.data_dir.join("sst");
+        let old = self.current();
+        let mut sstables = Vec::new();
+        for meta in &state.tables {
+            if let Some(table) = old.sstables.iter().find(|t| t.0.id == meta.id) {
+                sstables.push(table.clone());
+            } else if let Some(table) = open_table(&sst_dir, meta, db_id, &inner.block_cache)? {
+                sstables.push(table);
+            } else {
+                let path = table_path(&sst_dir, meta.id);
+                return Ok(Err(format!("{} is missing", path.display())));
+            }
+        }
+
+        let wal_ids = match wal::list_wal_ids(&inner.data_dir) {
+            Ok(ids) => ids,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut memtables = MemTableSet::with_capacity(usize::MAX);
+        let mut max_seq = 0;
+        let newest = wal_ids.last().copied();
+        for id in wal_ids {
+            let mem = MemTable::new(usize::MAX);
+            let path = wal::wal_path(&inner.data_dir, id);
+            match wal::replay_wal(&path, &mem, Some(id) == newest) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            max_seq = max_seq.max(mem.max_seq());
+            memtables.push_frozen(mem);
+        }
+        // The writer records a flush before deleting its segment, so an
+        // unchanged `last_seq` means no segment was missed.
+        match read()? {
+            Ok(now) if now.last_seq == state.last_seq => {}
+            Ok(_) => return Ok(Err("the writer flushed during the refresh".to_string())),
+            Err(why) => return Ok(Err(why)),
+        }
+
+        let mut version = Version {
             memtables,
             sstables,
-            block_bytes,
-            actor_id: 0,
-            local_counter: AtomicU64::new(0),
-            next_table_id,
-            manifest,
-        })
+            db_id,
+        };
+        version.sort_tables();
+        Ok(Ok((version, state.last_seq.max(max_seq))))
+    }
+
+    /// Replays old WAL segments oldest first, flushing each one to its own
+    /// SSTable before deleting it, so recovered tables keep write order.
+    /// Runs in the foreground: nothing is served until recovery is done.
+    fn recover_wals(&self, w: &mut Writer, wal_ids: &[u64]) -> std::io::Result<()> {
+        for &id in wal_ids {
+            let path = wal::wal_path(&self.inner.data_dir, id);
+            let mem = MemTable::new(usize::MAX);
+            wal::replay_wal(&path, &mem, false)?;
+            self.inner
+                .last_seq
+                .fetch_max(mem.max_seq(), Ordering::SeqCst);
+            if !mem.is_empty() {
+                let table_id = self.alloc_table_id();
+                let meta = write_level0_table(
+                    &mem,
+                    &self.inner.data_dir.join("sst"),
+                    &w.table_opts,
+                    table_id,
+                    self.smallest_snapshot(),
+                )?;
+                self.install_level0(w, meta, mem.max_seq(), None)?;
+            }
+            wal::remove_wal(&path)?;
+        }
+        Ok(())
     }
 
     pub fn new_with_manifest_and_actor(
@@ -83,106 +590,313 @@ impl LsmEngine {
         actor_id: u64,
     ) -> std::io::Result<Self> {
         let mut eng = Self::new_with_manifest(data_dir, memtable_size, block_size)?;
-        eng.actor_id = actor_id;
-        eng.local_counter = AtomicU64::new(1);
+        let inner = Arc::get_mut(&mut eng.inner).expect("engine was just opened");
+        inner.actor_id = actor_id;
+        inner.local_counter = AtomicU64::new(1);
         Ok(eng)
     }
 
+    pub fn actor_id(&self) -> u64 {
+        self.inner.actor_id
+    }
+
     /// Generate a fresh ElementId for local inserts.
     pub fn next_element_id(&self) -> ElementId {
-        let ctr = self.local_counter.fetch_add(1, Ordering::SeqCst);
-        ElementId::new(self.actor_id, ctr)
+        let ctr = self.inner.local_counter.fetch_add(1, Ordering::SeqCst);
+        ElementId::new(self.inner.actor_id, ctr)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.put(key, value) {
-            self.flush_immutable(frozen)?;
+    /// Deletes every key in `[start, end)` with a single range tombstone,
+    /// however many keys the range holds. Does nothing if `start >= end`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start, end);
+        self.write(batch)
+    }
+
+    /// Applies every operation in `batch` atomically: readers and snapshots
+    /// see all of them or none, they share one WAL record, and a memtable
+    /// rotation only happens after the whole batch is in.
+    ///
+    /// A failed flush or compaction fails this and every later write before
+    /// anything is applied. An error is never returned for a batch that was
+    /// applied: if the rotation after it fails, the next write reports it.
+    pub fn write(&self, batch: WriteBatch) -> std::io::Result<()> {
+        let mut w = self.writer()?;
+        self.poll_background(&mut w)?;
+        check_bg_error(&w)?;
+        let ops = self.resolve(batch)?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let first_seq = self.inner.last_seq.load(Ordering::SeqCst) + 1;
+        w.wal.append_batch(first_seq, &ops)?;
+        let version = self.current();
+        let mut full = false;
+        for (seq, op) in (first_seq..).zip(&ops) {
+            full = match op {
+                LoggedOp::Put(key, value) => version.memtables.put(key, seq, value),
+                LoggedOp::Delete(key) => version.memtables.delete(key, seq),
+                LoggedOp::DeleteRange(start, end) => {
+                    version.memtables.delete_range(start, end, seq)
+                }
+            };
+        }
+        // Reads stop at `last_seq`, so the batch becomes visible here.
+        self.inner
+            .last_seq
+            .store(first_seq + ops.len() as u64 - 1, Ordering::SeqCst);
+        if full {
+            if let Err(e) = self.rotate_memtable(&mut w) {
+                set_bg_error(&mut w, e);
+            }
         }
         Ok(())
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.delete(key) {
-            self.flush_immutable(frozen)?;
+    /// Turns a batch into plain puts, deletes and range deletes, computing
+    /// CRDT updates from the current value as changed by the batch so far.
+    /// Called with the write lock held, so no concurrent update is lost.
+    fn resolve(&self, batch: WriteBatch) -> std::io::Result<Vec<LoggedOp>> {
+        let mut ops: Vec<LoggedOp> = Vec::with_capacity(batch.len());
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Put(key, value) => ops.push(LoggedOp::Put(key, value)),
+                BatchOp::Delete(key) => ops.push(LoggedOp::Delete(key)),
+                BatchOp::DeleteRange(start, end) => ops.push(LoggedOp::DeleteRange(start, end)),
+                BatchOp::Update(key, update) => {
+                    let pending = ops.iter().rev().find_map(|op| match op {
+                        LoggedOp::Put(k, v) if *k == key => Some(Some(v.as_slice())),
+                        LoggedOp::Delete(k) if *k == key => Some(None),
+                        LoggedOp::DeleteRange(start, end)
+                            if start.as_slice() <= key.as_slice()
+                                && key.as_slice() < end.as_slice() =>
+                        {
+                            Some(None)
+                        }
+                        _ => None,
+                    });
+                    let new = match pending {
+                        Some(old) => update.apply(old),
+                        None => update.apply(self.get(&key)?.as_deref()),
+                    };
+                    if let Some(value) = new {
+                        ops.push(LoggedOp::Put(key, value));
+                    }
+                }
+            }
         }
-        Ok(())
+        Ok(ops)
     }
 
     pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
-        if let Some(entry) = self.memtables.get(key) {
+        self.get_seq(key, self.visible_seq())
+    }
+
+    /// Pins the current state; reads through the handle ignore later writes.
+    /// The sequence is read under the snapshot list's lock, so a compaction
+    /// sampling `smallest_snapshot` meanwhile either sees the new snapshot
+    /// or a sequence no newer than it.
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.snapshots.acquire(|| self.visible_seq())
+    }
+
+    /// Sequence number of the last write readers may see. Writes with
+    /// higher numbers may already be in a memtable, but their batch is not
+    /// complete yet.
+    fn visible_seq(&self) -> SeqNo {
+        self.inner.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Reads `key` as it was when `snapshot` was taken.
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        self.get_seq(key, snapshot.seq())
+    }
+
+    fn get_seq(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Option<Vec<u8>>> {
+        let version = self.current();
+        if let Some(entry) = version.memtables.get_at(key, seq) {
             return Ok(match entry {
-                Entry::Put(v) => Some(v.clone()),
+                Entry::Put(v) => Some(v),
                 Entry::Delete => None,
             });
         }
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(v) = reader.get(key)? {
-                return Ok(Some(v));
+        for (meta, _path, reader) in version.sstables.iter() {
+            if !meta.may_contain(key) {
+                continue;
+            }
+            match reader.get_at(key, seq)? {
+                Lookup::Found(v) => return Ok(Some(v)),
+                Lookup::Deleted => return Ok(None),
+                Lookup::Absent => {}
             }
         }
         Ok(None)
     }
 
-    pub fn flush(&mut self) -> std::io::Result<()> {
-        if let Some(frozen) = self.memtables.rotate() {
-            self.flush_immutable(frozen)?;
-        }
-        Ok(())
+    /// Iterates live keys in `[start, end)` in key order.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> ScanIter<'static> {
+        self.scan_range(start, Some(end.to_vec()), self.visible_seq())
     }
 
-    pub fn gset_add(&mut self, key: Vec<u8>, elem: Vec<u8>) -> std::io::Result<()> {
-        use crate::engine::crdt::{GSet, CRDT};
+    /// Iterates live keys starting with `prefix` in key order.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> ScanIter<'static> {
+        self.scan_range(prefix, prefix_end(prefix), self.visible_seq())
+    }
 
-        if let Some(entry) = self.memtables.get(&key) {
-            if let crate::storage::memtable::Entry::Put(existing_bytes) = entry {
-                let mut gs = GSet::from_bytes(existing_bytes);
-                gs.insert(elem);
-                let new_bytes = gs.to_bytes();
-                if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-                    self.flush_immutable(frozen)?;
-                }
-                return Ok(());
+    /// Like `scan`, but as of `snapshot`.
+    pub fn scan_at(&self, snapshot: &Snapshot, start: &[u8], end: &[u8]) -> ScanIter<'static> {
+        self.scan_range(start, Some(end.to_vec()), snapshot.seq())
+    }
+
+    /// Like `prefix_scan`, but as of `snapshot`.
+    pub fn prefix_scan_at(&self, snapshot: &Snapshot, prefix: &[u8]) -> ScanIter<'static> {
+        self.scan_range(prefix, prefix_end(prefix), snapshot.seq())
+    }
+
+    /// Merges the current version's sources. The iterator holds its own
+    /// handles to them, so it keeps working across later flushes and
+    /// compactions.
+    fn scan_range(&self, start: &[u8], end: Option<Vec<u8>>, seq: SeqNo) -> ScanIter<'static> {
+        let version = self.current();
+        let mut sources: Vec<EntryIter<'static>> = Vec::new();
+        let mut tombstones = Vec::new();
+        for mt in version.memtables.tables() {
+            sources.push(Box::new(MemTableIter::new(mt.clone(), start).map(Ok)));
+            tombstones.extend(mt.range_tombstones());
+        }
+        for (meta, _path, reader) in version.sstables.iter() {
+            let before_start = meta.largest.as_slice() < start;
+            let past_end = end
+                .as_deref()
+                .is_some_and(|end| meta.smallest.as_slice() >= end);
+            if before_start || past_end {
+                continue;
             }
+            tombstones.extend(reader.range_tombstones().iter().cloned());
+            sources.push(Box::new(SsTableIter::new_shared(
+                reader.clone(),
+                Some(start),
+            )));
         }
+        ScanIter::new(MergingIter::new(sources), end, seq).with_range_tombstones(tombstones)
+    }
 
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(bytes) = reader.get(&key)? {
-                let mut gs = GSet::from_bytes(&bytes);
-                gs.insert(elem);
-                let new_bytes = gs.to_bytes();
-                if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-                    self.flush_immutable(frozen)?;
-                }
-                return Ok(());
+    /// Freezes the active memtable and waits until every frozen memtable
+    /// has been written and installed as a table.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut w = self.writer()?;
+        check_bg_error(&w)?;
+        self.rotate_memtable(&mut w)?;
+        while self.wait_flush(&mut w)? {}
+        Ok(())
+    }
+
+    /// Runs compactions until every level is within its size target,
+    /// blocking on the background worker.
+    pub fn compact(&self) -> std::io::Result<()> {
+        let mut w = self.writer()?;
+        check_bg_error(&w)?;
+        loop {
+            if let Some((task, res)) = w.compactor.wait_finished() {
+                self.complete_compaction(&mut w, task, res)?;
+            }
+            match pick_compaction(
+                &self.current().table_metas(),
+                &self.inner.compaction,
+                self.smallest_snapshot(),
+            ) {
+                Some(task) => w.compactor.submit(task),
+                None => return Ok(()),
             }
         }
+    }
+
+    /// Number of live tables in each level, from level 0 down.
+    pub fn level_table_counts(&self) -> Vec<usize> {
+        self.level_stats().iter().map(|l| l.tables).collect()
+    }
 
-        let mut gs = GSet::new();
-        gs.insert(elem);
-        let new_bytes = gs.to_bytes();
-        if let Some(frozen) = self.memtables.put(&key, &new_bytes) {
-            self.flush_immutable(frozen)?;
+    /// Tables, bytes and entries in each level, from level 0 down, without
+    /// touching any table file.
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        let mut levels = vec![LevelStats::default(); self.inner.compaction.max_levels];
+        for (meta, _, _) in &self.current().sstables {
+            if meta.level >= levels.len() {
+                levels.resize(meta.level + 1, LevelStats::default());
+            }
+            let level = &mut levels[meta.level];
+            level.tables += 1;
+            level.bytes += meta.file_len;
+            level.entries += meta.num_entries;
+            level.deletions += meta.num_deletions;
         }
-        Ok(())
+        levels
+    }
+
+    /// Unique id of this database, recorded in the manifest and in every
+    /// table written for it; 0 for engines created without a manifest.
+    pub fn db_id(&self) -> u128 {
+        self.current().db_id
+    }
+
+    /// Hit/miss counters and current size of the shared block cache.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.inner.block_cache.stats()
     }
 
+    pub fn gset_add(&self, key: Vec<u8>, elem: Vec<u8>) -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.gset_add(key, elem);
+        self.write(batch)
+    }
+
+    /// Unions every stored version of the set, newest first, stopping at the
+    /// first tombstone, or range tombstone over the key, so a deleted set
+    /// does not come back.
     pub fn gset_get(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
         use crate::engine::crdt::{GSet, CRDT};
-        use crate::storage::memtable::Entry;
 
         let mut result = GSet::new();
+        let seq = self.visible_seq();
+        let version = self.current();
 
-        if let Some(entry) = self.memtables.get(key) {
-            if let Entry::Put(bytes) = entry {
-                let gs = GSet::from_bytes(bytes);
-                result.merge(&gs);
-            }
+        match version.memtables.get_at(key, seq) {
+            Some(Entry::Put(bytes)) => result.merge(&GSet::from_bytes(&bytes)),
+            Some(Entry::Delete) => return Ok(result.elements()),
+            None => {}
+        }
+        let range_deleted = version
+            .memtables
+            .tables()
+            .any(|mt| range_del::newest_covering(&mt.range_tombstones(), key, seq).is_some());
+        if range_deleted {
+            return Ok(result.elements());
         }
 
-        for (_, _path, reader) in self.sstables.iter().rev() {
-            if let Some(bytes) = reader.get(key)? {
-                let gs = GSet::from_bytes(&bytes);
-                result.merge(&gs);
+        for (meta, _path, reader) in version.sstables.iter() {
+            if !meta.may_contain(key) {
+                continue;
+            }
+            match reader.get_at(key, seq)? {
+                Lookup::Found(bytes) => result.merge(&GSet::from_bytes(&bytes)),
+                Lookup::Deleted => break,
+                Lookup::Absent => {}
+            }
+            if range_del::newest_covering(reader.range_tombstones(), key, seq).is_some() {
+                break;
             }
         }
 
@@ -190,50 +904,22 @@ impl LsmEngine {
     }
 
     pub fn rga_insert_after(
-        &mut self,
+        &self,
         key: &[u8],
         prev: Option<ElementId>,
         value: Vec<u8>,
         actor_id: u64,
         counter: u64,
     ) -> std::io::Result<()> {
-        let mut rga = match self.get(key)? {
-            Some(bs) => Rga::from_bytes(&bs),
-            None => Rga::new(),
-        };
-
-        let id = ElementId::new(actor_id, counter);
-        rga.insert(id, prev, value.clone());
-
-        // println!(
-        //     "RGA INSERT -> key={}, id=({}:{}) prev={:?} value='{}'",
-        //     String::from_utf8_lossy(key),
-        //     actor_id,
-        //     counter,
-        //     prev,
-        //     String::from_utf8_lossy(&value)
-        // );
-
-        // println!(
-        //     "Current sequence for '{}': {:?}",
-        //     String::from_utf8_lossy(key),
-        //     rga.visible_sequence()
-        //         .iter()
-        //         .map(|v| String::from_utf8_lossy(v).to_string())
-        //         .collect::<Vec<_>>()
-        // );
-
-        let bytes = rga.to_bytes();
-        self.put(key, &bytes)
+        let mut batch = WriteBatch::new();
+        batch.rga_insert_after(key, prev, value, actor_id, counter);
+        self.write(batch)
     }
 
-    pub fn rga_delete(&mut self, key: &[u8], id: ElementId) -> std::io::Result<()> {
-        let mut rga = match self.get(key)? {
-            Some(bs) => Rga::from_bytes(&bs),
-            None => return Ok(()), // kuch nai hein delete karne ko
-        };
-        rga.delete(id);
-        self.put(key, &rga.to_bytes())
+    pub fn rga_delete(&self, key: &[u8], id: ElementId) -> std::io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.rga_delete(key, id);
+        self.write(batch)
     }
 
     pub fn rga_get_visible(&self, key: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
@@ -246,34 +932,1313 @@ impl LsmEngine {
         }
     }
 
-    fn flush_immutable(&mut self, frozen: MemTable) -> Result<(), std::io::Error> {
-        let id = self.alloc_table_id();
-        let tmp = self.sst_tmp_path(id);
-        let final_path = self.sst_final_path(id);
+    /// Freezes the active memtable, if it holds anything, switches writes to
+    /// a fresh WAL segment and hands the frozen memtable and the old segment
+    /// to the flusher. Stalls while more frozen memtables are waiting than
+    /// `max_immutable_memtables` allows.
+    fn rotate_memtable(&self, w: &mut Writer) -> std::io::Result<()> {
+        // Replay only forgives a torn record in the newest segment, so the
+        // old one must be whole and durable before a newer one exists.
+        w.wal.sync()?;
+        let Some(frozen) = self.edit_version(|v| v.memtables.rotate()) else {
+            return Ok(());
+        };
+        let wal_id = w.wal.id() + 1;
+        let next = WalWriter::create(
+            wal::wal_path(&self.inner.data_dir, wal_id),
+            wal_id,
+            self.inner.wal_sync,
+        )?;
+        let old = std::mem::replace(&mut w.wal, next);
+        let wal_path = old.path().to_path_buf();
+        drop(old);
+        let submitted = w.flusher.submit(FlushTask {
+            mem: frozen,
+            wal_path,
+            smallest_snapshot: self.smallest_snapshot(),
+        });
+        submitted.map_err(|e| set_bg_error(w, e))?;
+        while self.current().memtables.immutables_len() > self.inner.max_immutables {
+            if !self.wait_flush(w)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the oldest in-flight flush and installs it; false if none
+    /// was in flight.
+    fn wait_flush(&self, w: &mut Writer) -> std::io::Result<bool> {
+        match w.flusher.wait_finished() {
+            Ok(Some((task, res))) => self.complete_flush(w, task, res).map(|_| true),
+            Ok(None) => Ok(false),
+            Err(e) => Err(set_bg_error(w, e)),
+        }
+    }
 
-        let _ = fs::create_dir_all(final_path.parent().unwrap());
-        let _res = flush_memtable_to_sstable(frozen, &tmp, self.block_bytes)?;
+    fn complete_flush(
+        &self,
+        w: &mut Writer,
+        task: FlushTask,
+        res: std::io::Result<TableMeta>,
+    ) -> std::io::Result<()> {
+        check_bg_error(w)?;
+        let installed = res.and_then(|meta| self.install_flush(w, task, meta));
+        installed.map_err(|e| set_bg_error(w, e))
+    }
 
-        fs::rename(&tmp, &final_path)?;
-        fsync_dir(&final_path)?;
-        self.manifest.record_add_table(id)?;
+    /// Records a flushed table, retires the memtable it was written from and
+    /// deletes that memtable's WAL segment.
+    fn install_flush(
+        &self,
+        w: &mut Writer,
+        task: FlushTask,
+        meta: TableMeta,
+    ) -> std::io::Result<()> {
+        self.install_level0(w, meta, task.mem.max_seq(), Some(&task.mem))?;
+        wal::remove_wal(&task.wal_path)
+    }
 
-        let reader = SsTableReader::open(&final_path)?;
-        self.sstables.push((id, final_path, reader));
+    /// Records a level-0 table and publishes it, in the same version that
+    /// drops the memtable it was flushed from, if any, so readers never see
+    /// the data in neither or both.
+    fn install_level0(
+        &self,
+        w: &mut Writer,
+        meta: TableMeta,
+        max_seq: SeqNo,
+        flushed: Option<&Arc<MemTable>>,
+    ) -> std::io::Result<()> {
+        let path = self.sst_final_path(meta.id);
+        let reader = SsTableReader::open_cached(&path, meta.id, &self.inner.block_cache)?;
+        w.manifest.record_flush(&meta, max_seq)?;
+        self.edit_version(|v| {
+            v.sstables.push((meta, path, Arc::new(reader)));
+            v.sort_tables();
+            if let Some(mem) = flushed {
+                v.memtables.remove_immutable(mem);
+            }
+        });
+        self.maybe_checkpoint_manifest(w)?;
+        self.maybe_schedule_compaction(w);
+        Ok(())
+    }
+
+    /// Rewrites the manifest as just the live table set once its edit log
+    /// has grown past `manifest_max_bytes`.
+    fn maybe_checkpoint_manifest(&self, w: &mut Writer) -> std::io::Result<()> {
+        if w.manifest.len() >= self.inner.manifest_max_bytes {
+            w.manifest.checkpoint(&self.inner.data_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Installs whatever the flusher and compactor have finished. Any error
+    /// is kept in `bg_error`, so it fails every later write.
+    fn poll_background(&self, w: &mut Writer) -> std::io::Result<()> {
+        loop {
+            match w.flusher.try_finished() {
+                Ok(Some((task, res))) => self.complete_flush(w, task, res)?,
+                Ok(None) => break,
+                Err(e) => return Err(set_bg_error(w, e)),
+            }
+        }
+        self.poll_compaction(w)
+    }
+
+    /// Installs a finished compaction, if any, and schedules the next one.
+    fn poll_compaction(&self, w: &mut Writer) -> std::io::Result<()> {
+        if let Some((task, res)) = w.compactor.try_finished() {
+            self.complete_compaction(w, task, res)?;
+            self.maybe_schedule_compaction(w);
+        }
         Ok(())
     }
 
-    fn sst_tmp_path(&self, id: TableId) -> PathBuf {
-        self.data_dir.join("sst").join(format!("{id:06}.sst.tmp"))
+    /// Installs a compaction the worker finished, keeping any error of
+    /// either in `bg_error`.
+    fn complete_compaction(
+        &self,
+        w: &mut Writer,
+        task: CompactionTask,
+        res: std::io::Result<Vec<TableMeta>>,
+    ) -> std::io::Result<()> {
+        let installed = res.and_then(|outputs| self.install_compaction(w, task, outputs));
+        installed.map_err(|e| set_bg_error(w, e))
+    }
+
+    fn maybe_schedule_compaction(&self, w: &mut Writer) {
+        if w.compactor.is_busy() {
+            return;
+        }
+        if let Some(task) = pick_compaction(
+            &self.current().table_metas(),
+            &self.inner.compaction,
+            self.smallest_snapshot(),
+        ) {
+            w.compactor.submit(task);
+        }
+    }
+
+    /// Oldest sequence number any reader may still need: the oldest live
+    /// snapshot, or the latest write when there are none.
+    fn smallest_snapshot(&self) -> SeqNo {
+        self.inner
+            .snapshots
+            .oldest_or(|| self.inner.last_seq.load(Ordering::SeqCst))
+    }
+
+    /// Opens the compaction's outputs, records them and the removed inputs
+    /// as one manifest edit, swaps them into the live table set, then
+    /// deletes the inputs. Nothing fallible runs between recording the edit
+    /// and publishing it, so the manifest and the live version agree.
+    /// Readers still holding an older version keep the inputs open until
+    /// they let go of it.
+    fn install_compaction(
+        &self,
+        w: &mut Writer,
+        task: CompactionTask,
+        outputs: Vec<TableMeta>,
+    ) -> std::io::Result<()> {
+        let removed: Vec<TableId> = task.inputs.iter().map(|m| m.id).collect();
+        let mut opened = Vec::with_capacity(outputs.len());
+        for meta in &outputs {
+            let path = self.sst_final_path(meta.id);
+            let reader = SsTableReader::open_cached(&path, meta.id, &self.inner.block_cache)?;
+            opened.push((meta.clone(), path, Arc::new(reader)));
+        }
+        w.manifest.record_edit(&outputs, &removed)?;
+        self.edit_version(|v| {
+            v.sstables
+                .retain(|(meta, _, _)| !removed.contains(&meta.id));
+            v.sstables.extend(opened);
+            v.sort_tables();
+        });
+        self.maybe_checkpoint_manifest(w)?;
+
+        for id in removed {
+            self.inner.block_cache.evict_table(id);
+            let path = self.sst_final_path(id);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        fsync_dir(&self.sst_final_path(0))
+    }
+
+    /// The version reads should search now.
+    fn current(&self) -> Arc<Version> {
+        self.inner.version.read().unwrap().clone()
+    }
+
+    /// Publishes a copy of the current version changed by `edit`. Only
+    /// called with the write lock held, so edits never race each other.
+    fn edit_version<R>(&self, edit: impl FnOnce(&mut Version) -> R) -> R {
+        let mut current = self.inner.version.write().unwrap();
+        let mut next = Version::clone(&current);
+        let res = edit(&mut next);
+        *current = Arc::new(next);
+        res
+    }
+
+    /// The write lock, or a `ReadOnlyError`.
+    fn writer(&self) -> std::io::Result<MutexGuard<'_, Writer>> {
+        match &self.inner.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(read_only_error()),
+        }
     }
 
     fn sst_final_path(&self, id: TableId) -> PathBuf {
-        self.data_dir.join("sst").join(format!("{id:06}.sst"))
+        table_path(&self.inner.data_dir.join("sst"), id)
+    }
+
+    fn alloc_table_id(&self) -> TableId {
+        self.inner.next_table_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// Records a failed flush so every later write fails too, returning the
+/// error for the caller to report.
+/// Keeps the first background error; later ones usually follow from it.
+fn set_bg_error(w: &mut Writer, e: std::io::Error) -> std::io::Error {
+    if w.bg_error.is_none() {
+        w.bg_error = Some((e.kind(), e.to_string()));
+    }
+    e
+}
+
+fn check_bg_error(w: &Writer) -> std::io::Result<()> {
+    match &w.bg_error {
+        Some((kind, msg)) => Err(std::io::Error::new(
+            *kind,
+            format!("background flush or compaction failed: {msg}"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Deletes what a crash can leave in `sst/`: temporary files of tables that
+/// were never renamed into place and, with `tables` set, tables missing
+/// from the manifest because the flush or compaction that wrote them was
+/// never recorded, or its inputs were not deleted yet.
+fn remove_orphan_files(
+    data_dir: &Path,
+    live: &[TableMeta],
+    tables: bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let sst_dir = data_dir.join("sst");
+    let mut removed = Vec::new();
+    let mut deleted = false;
+    for entry in fs::read_dir(&sst_dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let unreferenced =
+            table_id_from_name(&name).is_some_and(|id| !live.iter().any(|m| m.id == id));
+        if name.ends_with(".sst.tmp") || (tables && unreferenced) {
+            fs::remove_file(&path)?;
+            deleted = true;
+            removed.push(path);
+        }
+    }
+    if deleted {
+        fsync_dir(&table_path(&sst_dir, 0))?;
+    }
+    Ok(removed)
+}
+
+/// Largest id of a finished table file in `sst_dir`, or 0 if there is none.
+fn max_table_id_in(sst_dir: &Path) -> std::io::Result<TableId> {
+    let mut max = 0;
+    for entry in fs::read_dir(sst_dir)? {
+        let name = entry?.file_name();
+        if let Some(id) = table_id_from_name(&name.to_string_lossy()) {
+            max = max.max(id);
+        }
+    }
+    Ok(max)
+}
+
+/// Times `refresh` reads the writer's state before giving up.
+const REFRESH_ATTEMPTS: usize = 5;
+
+fn read_only_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, ReadOnlyError)
+}
+
+/// Opens the reader of live table `meta`, or returns `None` if its file is
+/// gone. Tables recorded without metadata get it from the file. Fails if the
+/// file was written for another database than `db_id` (0 if unknown).
+fn open_table(
+    sst_dir: &Path,
+    meta: &TableMeta,
+    db_id: u128,
+    cache: &BlockCache,
+) -> std::io::Result<Option<(TableMeta, PathBuf, Arc<SsTableReader>)>> {
+    let path = table_path(sst_dir, meta.id);
+    let reader = match SsTableReader::open_cached(&path, meta.id, cache) {
+        Ok(reader) => reader,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if db_id != 0 && reader.db_id().is_some_and(|id| id != db_id) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} belongs to another database", path.display()),
+        ));
+    }
+    let meta = if meta.file_len == 0 {
+        describe_table(meta, &path, &reader)?
+    } else {
+        meta.clone()
+    };
+    Ok(Some((meta, path, Arc::new(reader))))
+}
+
+/// Builds full metadata for a table recorded by an older manifest with only
+/// its id and level, from its properties block or else by reading it once.
+fn describe_table(
+    meta: &TableMeta,
+    path: &Path,
+    reader: &SsTableReader,
+) -> std::io::Result<TableMeta> {
+    if let Some(props) = reader.properties() {
+        let points = (props.num_entries > 0)
+            .then(|| (props.smallest_key.clone(), props.largest_key.clone()));
+        let (smallest, largest) = range_del::key_bounds(points, reader.range_tombstones());
+        return Ok(TableMeta {
+            smallest,
+            largest,
+            file_len: fs::metadata(path)?.len(),
+            num_entries: props.num_entries,
+            num_deletions: props.num_deletions,
+            ..meta.clone()
+        });
+    }
+    let mut described = TableMeta {
+        smallest: reader.first_key()?.unwrap_or_default(),
+        largest: reader.last_key().unwrap_or_default(),
+        file_len: fs::metadata(path)?.len(),
+        ..meta.clone()
+    };
+    for item in SsTableIter::new_seek(reader, None) {
+        let (_, _, entry) = item?;
+        described.num_entries += 1;
+        if matches!(entry, Entry::Delete) {
+            described.num_deletions += 1;
+        }
+    }
+    Ok(described)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zynk-kv-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn unflushed_writes_survive_reopen() {
+        let dir = temp_dir("wal-reopen");
+        {
+            let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.put(b"b", b"2").unwrap();
+            eng.delete(b"a").unwrap();
+        }
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+        assert_eq!(eng.get(b"a").unwrap(), None);
+        assert_eq!(eng.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(wal::list_wal_ids(&dir).unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compaction_merges_levels_and_removes_inputs() {
+        let dir = temp_dir("compact");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 256,
+            compaction: CompactionOptions {
+                level1_max_bytes: 4 * 1024,
+                target_table_bytes: 2 * 1024,
+                ..CompactionOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        {
+            let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            for i in 0..2000u32 {
+                let key = format!("key{:04}", i % 500);
+                eng.put(key.as_bytes(), format!("v{i}").as_bytes()).unwrap();
+            }
+            for i in 0..100u32 {
+                eng.delete(format!("key{i:04}").as_bytes()).unwrap();
+            }
+            eng.flush().unwrap();
+            eng.compact().unwrap();
+
+            let counts = eng.level_table_counts();
+            assert!(counts[0] < opts.compaction.l0_trigger);
+            assert!(counts[1..].iter().sum::<usize>() > 0);
+            let on_disk = fs::read_dir(dir.join("sst")).unwrap().count();
+            assert_eq!(on_disk, counts.iter().sum::<usize>());
+        }
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.get(b"key0050").unwrap(), None);
+        assert_eq!(eng.get(b"key0499").unwrap(), Some(b"v1999".to_vec()));
+        assert_eq!(eng.get(b"key0100").unwrap(), Some(b"v1600".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn scan_merges_memtable_and_tables_newest_wins() {
+        let dir = temp_dir("scan");
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 128).unwrap();
+        for i in 0..50u32 {
+            eng.put(format!("user/{i:03}").as_bytes(), b"old").unwrap();
+        }
+        eng.put(b"other", b"x").unwrap();
+        eng.flush().unwrap();
+        eng.put(b"user/010", b"new").unwrap();
+        eng.delete(b"user/011").unwrap();
+        eng.put(b"user/100", b"fresh").unwrap();
+
+        let rows: Vec<_> = eng
+            .scan(b"user/009", b"user/013")
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (b"user/009".to_vec(), b"old".to_vec()),
+                (b"user/010".to_vec(), b"new".to_vec()),
+                (b"user/012".to_vec(), b"old".to_vec()),
+            ]
+        );
+
+        let keys: Vec<_> = eng.prefix_scan(b"user/").map(|r| r.unwrap().0).collect();
+        assert_eq!(keys.len(), 50);
+        assert_eq!(keys.last().unwrap(), b"user/100");
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn delete_shadows_value_across_flush_boundary() {
+        let dir = temp_dir("tombstone");
+        {
+            let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+            eng.put(b"k", b"v1").unwrap();
+            eng.gset_add(b"set".to_vec(), b"a".to_vec()).unwrap();
+            eng.flush().unwrap();
+            eng.delete(b"k").unwrap();
+            eng.delete(b"set").unwrap();
+            eng.flush().unwrap();
+            assert_eq!(eng.get(b"k").unwrap(), None);
+            assert!(eng.gset_get(b"set").unwrap().is_empty());
+
+            eng.gset_add(b"set".to_vec(), b"b".to_vec()).unwrap();
+            assert_eq!(eng.gset_get(b"set").unwrap(), vec![b"b".to_vec()]);
+        }
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+        assert_eq!(eng.get(b"k").unwrap(), None);
+        assert_eq!(eng.gset_get(b"set").unwrap(), vec![b"b".to_vec()]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn snapshot_reads_survive_overwrites_flush_and_compaction() {
+        let dir = temp_dir("snapshot");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 256,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for i in 0..20u32 {
+            eng.put(format!("k{i:02}").as_bytes(), b"old").unwrap();
+        }
+        let snap = eng.snapshot();
+        for i in 0..20u32 {
+            eng.put(format!("k{i:02}").as_bytes(), b"new").unwrap();
+        }
+        eng.delete(b"k05").unwrap();
+        eng.put(b"k99", b"late").unwrap();
+        eng.flush().unwrap();
+        eng.compact().unwrap();
+
+        assert_eq!(eng.get_at(&snap, b"k05").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(eng.get_at(&snap, b"k99").unwrap(), None);
+        assert_eq!(eng.get(b"k05").unwrap(), None);
+        assert_eq!(eng.get(b"k06").unwrap(), Some(b"new".to_vec()));
+
+        let rows: Vec<_> = eng
+            .prefix_scan_at(&snap, b"k")
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(rows.len(), 20);
+        assert!(rows.iter().all(|(_, v)| v == b"old"));
+        let live: Vec<_> = eng.scan(b"k04", b"k07").map(|r| r.unwrap().0).collect();
+        assert_eq!(live, vec![b"k04".to_vec(), b"k06".to_vec()]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn block_cache_serves_hot_reads_and_drops_compacted_tables() {
+        let dir = temp_dir("block-cache");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 256,
+            ..EngineOptions::default()
+        };
+        {
+            let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            for i in 0..200u32 {
+                eng.put(format!("k{i:03}").as_bytes(), b"v").unwrap();
+            }
+            eng.flush().unwrap();
+            eng.compact().unwrap();
+        }
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for _ in 0..10 {
+            assert_eq!(eng.get(b"k150").unwrap(), Some(b"v".to_vec()));
+        }
+        let stats = eng.block_cache_stats();
+        assert!(stats.hits >= 9, "{stats:?}");
+        assert!(stats.entries > 0 && stats.bytes > 0);
+
+        // Rewriting every key compacts all the tables read above away.
+        for i in 0..200u32 {
+            eng.put(format!("k{i:03}").as_bytes(), b"w").unwrap();
+        }
+        eng.flush().unwrap();
+        eng.compact().unwrap();
+        assert_eq!(eng.block_cache_stats().entries, 0);
+        assert_eq!(eng.get(b"k150").unwrap(), Some(b"w".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compressed_tables_read_back_and_mix_with_uncompressed() {
+        let dir = temp_dir("compression");
+        let value = br#"{"name":"zynk","tags":["a","b","c"],"active":true}"#.repeat(4);
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            eng.put(b"plain", &value).unwrap();
+            eng.flush().unwrap();
+        }
+        let opts = EngineOptions {
+            compression: CompressionType::Snappy,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+        for i in 0..100u32 {
+            eng.put(format!("doc/{i:03}").as_bytes(), &value).unwrap();
+        }
+        eng.flush().unwrap();
+        let sizes: Vec<u64> = eng
+            .current()
+            .table_metas()
+            .iter()
+            .map(|m| m.file_len)
+            .collect();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes[0] < 100 * value.len() as u64 / 4, "{sizes:?}");
+        drop(eng);
+
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.get(b"plain").unwrap(), Some(value.clone()));
+        assert_eq!(eng.get(b"doc/042").unwrap(), Some(value.clone()));
+        assert_eq!(eng.prefix_scan(b"doc/").count(), 100);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn background_flush_caps_immutables_and_keeps_them_readable() {
+        let dir = temp_dir("bg-flush");
+        let opts = EngineOptions {
+            memtable_max_bytes: 256,
+            max_immutable_memtables: 1,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        for i in 0..300u32 {
+            eng.put(format!("k{i:03}").as_bytes(), b"value").unwrap();
+            assert!(eng.current().memtables.immutables_len() <= 1);
+        }
+        for i in 0..300u32 {
+            let key = format!("k{i:03}");
+            assert_eq!(eng.get(key.as_bytes()).unwrap(), Some(b"value".to_vec()));
+        }
+        eng.flush().unwrap();
+        assert_eq!(eng.current().memtables.immutables_len(), 0);
+        assert_eq!(wal::list_wal_ids(&dir).unwrap().len(), 1);
+        assert!(!eng.current().sstables.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_flush_fails_later_writes_and_flushes() {
+        let dir = temp_dir("flush-fails");
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        eng.put(b"k", b"v").unwrap();
+        fs::remove_dir_all(dir.join("sst")).unwrap();
+
+        let err = eng.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(eng.flush().is_err());
+        assert!(eng.put(b"k2", b"v").is_err());
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(eng.current().memtables.immutables_len(), 1);
+        drop(eng);
+
+        fs::create_dir_all(dir.join("sst")).unwrap();
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"v".to_vec()));
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn writes_are_rejected_up_front_after_a_background_failure() {
+        let dir = temp_dir("flush-fails-write");
+        let opts = EngineOptions {
+            memtable_max_bytes: 1024,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        fs::remove_dir_all(dir.join("sst")).unwrap();
+
+        let key = |i: u32| format!("k{i:05}");
+        let mut failed_at = None;
+        for i in 0..100_000u32 {
+            match eng.put(key(i).as_bytes(), b"v") {
+                Ok(()) => assert!(failed_at.is_none(), "put {i} after a failure"),
+                Err(_) => {
+                    failed_at.get_or_insert(i);
+                }
+            }
+            if failed_at.is_some_and(|f| i >= f + 3) {
+                break;
+            }
+        }
+        let failed_at = failed_at.expect("the flush never failed");
+        // Every put that succeeded is readable; none that failed is.
+        for i in 0..failed_at + 3 {
+            let found = eng.get(key(i).as_bytes()).unwrap().is_some();
+            assert_eq!(found, i < failed_at, "{}", key(i));
+        }
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn manifest_is_checkpointed_and_stale_manifests_are_removed() {
+        let dir = temp_dir("manifest-rotate");
+        let opts = EngineOptions {
+            memtable_max_bytes: 256,
+            manifest_max_bytes: 256,
+            ..EngineOptions::default()
+        };
+        {
+            let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            for i in 0..500u32 {
+                eng.put(
+                    format!("k{:03}", i % 200).as_bytes(),
+                    format!("v{i}").as_bytes(),
+                )
+                .unwrap();
+            }
+            eng.flush().unwrap();
+            eng.compact().unwrap();
+            assert!(eng.writer().unwrap().manifest.len() < 2 * 256);
+        }
+        let manifests = |dir: &Path| -> Vec<String> {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|n| n.starts_with("MANIFEST-"))
+                .collect()
+        };
+        let current = fs::read_to_string(dir.join("CURRENT")).unwrap();
+        assert_eq!(manifests(&dir), vec![current.trim().to_string()]);
+        assert_ne!(current.trim(), "MANIFEST-000001");
+
+        // A checkpoint that crashed before switching CURRENT leaves a stray file.
+        fs::write(dir.join("MANIFEST-999999"), b"add 1 0\n").unwrap();
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(manifests(&dir).len(), 1);
+        for i in 300..500u32 {
+            let key = format!("k{:03}", i % 200);
+            assert_eq!(
+                eng.get(key.as_bytes()).unwrap(),
+                Some(format!("v{i}").into_bytes())
+            );
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn table_metadata_is_persisted_and_feeds_level_stats() {
+        let dir = temp_dir("table-meta");
+        let opts = EngineOptions {
+            memtable_max_bytes: 1024,
+            block_bytes: 256,
+            compaction: CompactionOptions {
+                l0_trigger: 1000,
+                ..CompactionOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        let stats = {
+            let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+            for i in 0..300u32 {
+                eng.put(format!("k{i:03}").as_bytes(), b"value").unwrap();
+            }
+            for i in 0..30u32 {
+                eng.delete(format!("k{i:03}").as_bytes()).unwrap();
+            }
+            eng.flush().unwrap();
+            let stats = eng.level_stats();
+            assert_eq!(stats[0].tables, eng.level_table_counts()[0]);
+            assert_eq!(stats[0].entries, 330);
+            assert_eq!(stats[0].deletions, 30);
+            let on_disk: u64 = fs::read_dir(dir.join("sst"))
+                .unwrap()
+                .map(|e| e.unwrap().metadata().unwrap().len())
+                .sum();
+            assert_eq!(stats[0].bytes, on_disk);
+            stats
+        };
+
+        // Reopening takes key ranges and counts from the manifest, so a
+        // lookup outside every range never touches a table.
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.level_stats(), stats);
+        assert_eq!(eng.get(b"zzz").unwrap(), None);
+        assert_eq!(eng.get(b"a").unwrap(), None);
+        assert_eq!(eng.block_cache_stats().misses, 0);
+        assert_eq!(eng.get(b"k100").unwrap(), Some(b"value".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tables_from_another_database_or_renamed_tables_are_rejected() {
+        let dir_a = temp_dir("db-id-a");
+        let dir_b = temp_dir("db-id-b");
+        let mut ids = Vec::new();
+        for dir in [&dir_a, &dir_b] {
+            let eng = LsmEngine::open(dir, EngineOptions::default()).unwrap();
+            eng.put(b"one", b"1").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"two", b"2").unwrap();
+            eng.flush().unwrap();
+            ids.push(eng.db_id());
+        }
+        assert_ne!(ids[0], ids[1]);
+        let eng = LsmEngine::open(&dir_b, EngineOptions::default()).unwrap();
+        assert_eq!(eng.db_id(), ids[1]);
+        drop(eng);
+
+        let sst = |dir: &Path, id| table_path(&dir.join("sst"), id);
+        let b1 = fs::read(sst(&dir_b, 1)).unwrap();
+        fs::copy(sst(&dir_a, 1), sst(&dir_b, 1)).unwrap();
+        let err = LsmEngine::open(&dir_b, EngineOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("another database"), "{err}");
+        fs::write(sst(&dir_b, 1), &b1).unwrap();
+
+        fs::rename(sst(&dir_b, 1), dir_b.join("swap")).unwrap();
+        fs::rename(sst(&dir_b, 2), sst(&dir_b, 1)).unwrap();
+        fs::rename(dir_b.join("swap"), sst(&dir_b, 2)).unwrap();
+        let err = LsmEngine::open(&dir_b, EngineOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("holds table"), "{err}");
+        let _ = fs::remove_dir_all(&dir_a);
+        let _ = fs::remove_dir_all(&dir_b);
+    }
+
+    #[test]
+    fn data_dir_cannot_be_opened_by_two_engines() {
+        let dir = temp_dir("lock");
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        eng.put(b"k", b"v").unwrap();
+        let err = LsmEngine::open(&dir, EngineOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(LsmEngine::new_with_manifest(&dir, 1024, 1024).is_err());
+        drop(eng);
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"v".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_engine_follows_the_writer_without_writing() {
+        let dir = temp_dir("read-only");
+        let missing = dir.join("missing");
+        assert!(LsmEngine::open_read_only(&missing, EngineOptions::default()).is_err());
+        assert!(!missing.exists());
+
+        let opts = EngineOptions {
+            memtable_max_bytes: 1024,
+            ..EngineOptions::default()
+        };
+        let writer = LsmEngine::open(&dir, opts.clone()).unwrap();
+        for i in 0..100u32 {
+            writer.put(format!("k{i:03}").as_bytes(), b"old").unwrap();
+        }
+        writer.put(b"unflushed", b"wal").unwrap();
+
+        let listing = |dir: &Path| {
+            let mut names: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = (listing(&dir), listing(&wal::wal_dir(&dir)));
+        let reader = LsmEngine::open_read_only(&dir, opts).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get(b"k042").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(reader.get(b"unflushed").unwrap(), Some(b"wal".to_vec()));
+
+        let err = reader.put(b"k", b"v").unwrap_err();
+        assert!(ReadOnlyError::is(&err));
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(ReadOnlyError::is(&reader.delete(b"k042").unwrap_err()));
+        assert!(ReadOnlyError::is(
+            &reader.gset_add(b"set".to_vec(), b"x".to_vec()).unwrap_err()
+        ));
+        assert!(ReadOnlyError::is(&reader.flush().unwrap_err()));
+        assert!(ReadOnlyError::is(&reader.compact().unwrap_err()));
+        assert_eq!((listing(&dir), listing(&wal::wal_dir(&dir))), before);
+
+        for i in 0..100u32 {
+            writer.put(format!("k{i:03}").as_bytes(), b"new").unwrap();
+        }
+        writer.delete(b"unflushed").unwrap();
+        writer.flush().unwrap();
+        writer.compact().unwrap();
+        assert_eq!(reader.get(b"unflushed").unwrap(), Some(b"wal".to_vec()));
+
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(b"k042").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(reader.get(b"unflushed").unwrap(), None);
+        assert_eq!(reader.scan(b"k", b"l").count(), 100);
+        assert_eq!(reader.level_stats(), writer.level_stats());
+        let _ = fs::remove_dir_all(&dir);
     }
 
-    fn alloc_table_id(&mut self) -> TableId {
-        let id = self.next_table_id;
-        self.next_table_id += 1;
-        id
+    #[test]
+    fn refresh_fails_while_a_listed_table_is_missing() {
+        let dir = temp_dir("refresh-missing");
+        let writer = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        writer.put(b"a", b"1").unwrap();
+        writer.flush().unwrap();
+        let reader = LsmEngine::open_read_only(&dir, EngineOptions::default()).unwrap();
+
+        writer.put(b"a", b"2").unwrap();
+        writer.flush().unwrap();
+        let newest = writer.current().sstables.iter().map(|t| t.0.id).max();
+        let path = table_path(&dir.join("sst"), newest.unwrap());
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let err = reader.refresh().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        fs::write(&path, bytes).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"2".to_vec()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refresh_retries_when_a_checkpoint_removes_the_manifest() {
+        use crate::storage::manifest::current_path;
+
+        let dir = temp_dir("refresh-checkpoint");
+        let writer = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        writer.put(b"a", b"1").unwrap();
+        writer.flush().unwrap();
+        let reader = LsmEngine::open_read_only(&dir, EngineOptions::default()).unwrap();
+        writer.put(b"a", b"2").unwrap();
+        writer.flush().unwrap();
+
+        // Reads CURRENT, lets the writer checkpoint, then reads the manifest
+        // CURRENT named, which the checkpoint deleted.
+        let raced = std::cell::Cell::new(false);
+        reader
+            .refresh_with(|dir| {
+                if !raced.replace(true) {
+                    let named = fs::read_to_string(current_path(dir))?;
+                    let mut w = writer.writer().unwrap();
+                    w.manifest.checkpoint(dir).unwrap();
+                    fs::read(dir.join(named.trim()))?;
+                    unreachable!("the old manifest was deleted");
+                }
+                read_manifest_state(dir)
+            })
+            .unwrap();
+        assert!(raced.get());
+        assert_eq!(reader.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        let gone = |_: &Path| -> std::io::Result<ManifestState> {
+            Err(std::io::ErrorKind::NotFound.into())
+        };
+        let err = reader.refresh_with(gone).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unreferenced_tables_are_kept_when_the_manifest_lost_its_tail() {
+        let dir = temp_dir("orphans-torn");
+        let sst = dir.join("sst");
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.flush().unwrap();
+        }
+        fs::copy(table_path(&sst, 1), table_path(&sst, 50)).unwrap();
+        // The next id after the manifest's, as the lost flush would use.
+        fs::write(table_path(&sst, 2), b"written by a lost flush").unwrap();
+        let current = crate::storage::manifest::current_path(&dir);
+        let manifest = dir.join(fs::read_to_string(current).unwrap().trim());
+        let mut f = fs::OpenOptions::new().append(true).open(&manifest).unwrap();
+        std::io::Write::write_all(&mut f, &[64, 0, 0, 0, 1, 2]).unwrap();
+        drop(f);
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert!(eng.removed_orphans().is_empty());
+        assert!(table_path(&sst, 50).exists());
+        assert_eq!(eng.get(b"a").unwrap(), Some(b"1".to_vec()));
+        eng.put(b"b", b"2").unwrap();
+        eng.flush().unwrap();
+        let lost_flush = fs::read(table_path(&sst, 2)).unwrap();
+        assert_eq!(lost_flush, b"written by a lost flush");
+        assert!(table_path(&sst, 51).exists());
+        drop(eng);
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.get(b"b").unwrap(), Some(b"2".to_vec()));
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn open_removes_orphans_and_reports_missing_tables() {
+        use crate::storage::sstable::table_tmp_path;
+
+        let dir = temp_dir("orphans");
+        let sst = dir.join("sst");
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"b", b"2").unwrap();
+            eng.flush().unwrap();
+        }
+        fs::write(table_tmp_path(&sst, 3), b"half a table").unwrap();
+        fs::copy(table_path(&sst, 1), table_path(&sst, 50)).unwrap();
+        fs::write(sst.join("notes.txt"), b"keep me").unwrap();
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        let mut removed = eng.removed_orphans().to_vec();
+        removed.sort();
+        assert_eq!(removed, vec![table_tmp_path(&sst, 3), table_path(&sst, 50)]);
+        assert!(!table_path(&sst, 50).exists());
+        assert!(sst.join("notes.txt").exists());
+        assert_eq!(eng.get(b"a").unwrap(), Some(b"1".to_vec()));
+        drop(eng);
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert!(eng.removed_orphans().is_empty());
+        drop(eng);
+
+        let len = fs::metadata(table_path(&sst, 2)).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(table_path(&sst, 2))
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        fs::remove_file(table_path(&sst, 1)).unwrap();
+        let err = LsmEngine::open(&dir, EngineOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let msg = err.to_string();
+        assert!(msg.contains("000001.sst is missing"), "{msg}");
+        assert!(msg.contains("000002.sst is unreadable"), "{msg}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn engine_is_shared_between_reader_threads_without_a_lock() {
+        let dir = temp_dir("shared-reads");
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        for i in 0..600u32 {
+            eng.put(format!("k{i:04}").as_bytes(), format!("v{i}").as_bytes())
+                .unwrap();
+            if i % 200 == 199 {
+                eng.flush().unwrap();
+            }
+        }
+        let readers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let eng = eng.clone();
+                std::thread::spawn(move || {
+                    for i in (t..600).step_by(4) {
+                        let got = eng.get(format!("k{i:04}").as_bytes()).unwrap();
+                        assert_eq!(got, Some(format!("v{i}").into_bytes()));
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clones_write_concurrently_while_others_read_and_flush() {
+        let dir = temp_dir("shared-writes");
+        let opts = EngineOptions {
+            memtable_max_bytes: 2048,
+            wal_sync: WalSyncPolicy::Never,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        let writers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let eng = eng.clone();
+                std::thread::spawn(move || {
+                    for i in 0..300u32 {
+                        let key = format!("t{t}-{i:04}");
+                        eng.put(key.as_bytes(), b"v").unwrap();
+                        assert_eq!(eng.get(key.as_bytes()).unwrap(), Some(b"v".to_vec()));
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let eng = eng.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    let keys: Vec<_> = eng.prefix_scan(b"t0-").map(|r| r.unwrap().0).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+        eng.flush().unwrap();
+        assert!(eng.level_table_counts().iter().sum::<usize>() > 0);
+        assert_eq!(eng.scan(b"t", b"u").count(), 1200);
+        drop(eng);
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.scan(b"t", b"u").count(), 1200);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn concurrent_gset_adds_are_not_lost() {
+        let dir = temp_dir("shared-gset");
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        let adders: Vec<_> = (0..4u32)
+            .map(|t| {
+                let eng = eng.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50u32 {
+                        eng.gset_add(b"set".to_vec(), format!("{t}-{i}").into_bytes())
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for adder in adders {
+            adder.join().unwrap();
+        }
+        assert_eq!(eng.gset_get(b"set").unwrap().len(), 200);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_batch_applies_every_kind_of_op_in_one_wal_record() {
+        let dir = temp_dir("batch");
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            eng.put(b"obj/old", b"x").unwrap();
+            let first = ElementId::new(9, 1);
+            let mut batch = WriteBatch::new();
+            batch.put(b"obj/1", b"payload");
+            batch.put(b"idx/name/1", b"");
+            batch.delete(b"obj/old");
+            batch.gset_add(b"tags".to_vec(), b"a".to_vec());
+            batch.gset_add(b"tags".to_vec(), b"b".to_vec());
+            batch.rga_insert_after(b"list", None, b"h".to_vec(), 9, 1);
+            batch.rga_insert_after(b"list", Some(first), b"i".to_vec(), 9, 2);
+            batch.rga_delete(b"missing", first);
+            eng.write(batch).unwrap();
+
+            assert_eq!(eng.get(b"obj/1").unwrap(), Some(b"payload".to_vec()));
+            assert_eq!(eng.get(b"obj/old").unwrap(), None);
+            assert_eq!(eng.gset_get(b"tags").unwrap().len(), 2);
+            assert_eq!(
+                eng.rga_get_visible(b"list").unwrap(),
+                vec![b"h".to_vec(), b"i".to_vec()]
+            );
+            assert_eq!(eng.get(b"missing").unwrap(), None);
+
+            let wal_ids = wal::list_wal_ids(&dir).unwrap();
+            let mem = MemTable::new(usize::MAX);
+            let path = wal::wal_path(&dir, *wal_ids.last().unwrap());
+            assert_eq!(wal::replay_wal(&path, &mem, true).unwrap(), 2);
+            assert_eq!(mem.len(), 8);
+        }
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.get(b"idx/name/1").unwrap(), Some(Vec::new()));
+        assert_eq!(eng.gset_get(b"tags").unwrap().len(), 2);
+        assert_eq!(eng.rga_get_visible(b"list").unwrap().len(), 2);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_batch_is_not_split_across_memtables() {
+        let dir = temp_dir("batch-rotation");
+        let opts = EngineOptions {
+            memtable_max_bytes: 1024,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..100u32 {
+            batch.put(format!("k{i:03}").as_bytes(), &[0; 32]);
+        }
+        eng.write(batch).unwrap();
+        eng.flush().unwrap();
+        assert_eq!(eng.level_table_counts()[0], 1);
+        assert_eq!(eng.scan(b"k", b"l").count(), 100);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn delete_range_hides_keys_from_reads_scans_and_replay() {
+        let dir = temp_dir("delete-range");
+        let key = |i: u32| format!("key{i:04}").into_bytes();
+        let check = |eng: &LsmEngine| {
+            assert_eq!(eng.get(&key(99)).unwrap(), Some(b"old".to_vec()));
+            assert_eq!(eng.get(&key(100)).unwrap(), None);
+            assert_eq!(eng.get(&key(500)).unwrap(), None);
+            assert_eq!(eng.get(&key(200)).unwrap(), Some(b"back".to_vec()));
+            assert_eq!(eng.get(&key(900)).unwrap(), Some(b"old".to_vec()));
+            assert_eq!(eng.gset_get(b"key0300set").unwrap(), vec![b"b".to_vec()]);
+            let keys: Vec<_> = eng
+                .scan(&key(95), &key(905))
+                .map(|r| r.unwrap().0)
+                .collect();
+            assert_eq!(keys.len(), 5 + 2 + 5);
+            assert_eq!(keys[5], key(200));
+            assert_eq!(eng.prefix_scan(b"key").count(), 100 + 2 + 100);
+        };
+        {
+            let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+            for i in 0..1000u32 {
+                eng.put(&key(i), b"old").unwrap();
+            }
+            eng.gset_add(b"key0300set".to_vec(), b"a".to_vec()).unwrap();
+            eng.flush().unwrap();
+            eng.put(&key(500), b"newer").unwrap();
+            let snap = eng.snapshot();
+
+            let mut batch = WriteBatch::new();
+            batch.put(&key(600), b"in batch");
+            batch.delete_range(&key(100), &key(900));
+            batch.gset_add(b"key0300set".to_vec(), b"b".to_vec());
+            batch.delete_range(b"z", b"a");
+            eng.write(batch).unwrap();
+            eng.put(&key(200), b"back").unwrap();
+
+            check(&eng);
+            assert_eq!(
+                eng.get_at(&snap, &key(500)).unwrap(),
+                Some(b"newer".to_vec())
+            );
+            assert_eq!(eng.scan_at(&snap, &key(100), &key(900)).count(), 801);
+        }
+        let eng = LsmEngine::new_with_manifest(&dir, 64 * 1024, 4096).unwrap();
+        check(&eng);
+        eng.flush().unwrap();
+        check(&eng);
+        let l0 = &eng.current().sstables[0].2;
+        assert_eq!(l0.range_tombstones().len(), 1);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compaction_drops_versions_under_a_range_tombstone() {
+        let dir = temp_dir("delete-range-compact");
+        let opts = EngineOptions {
+            memtable_max_bytes: 512,
+            block_bytes: 256,
+            compaction: CompactionOptions {
+                target_table_bytes: 1024,
+                ..CompactionOptions::default()
+            },
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts.clone()).unwrap();
+        for i in 0..2000u32 {
+            let key = format!("key{:04}", i % 500);
+            eng.put(key.as_bytes(), format!("v{i}").as_bytes()).unwrap();
+        }
+        eng.flush().unwrap();
+        eng.compact().unwrap();
+        let entries = |eng: &LsmEngine| eng.level_stats().iter().map(|l| l.entries).sum::<u64>();
+        assert!(entries(&eng) >= 500);
+
+        eng.delete_range(b"key0100", b"key0400").unwrap();
+        // Enough flushes to merge every level-0 table, the tombstone's
+        // included, into level 1, which is the last level holding data.
+        for i in 0..opts.compaction.l0_trigger {
+            eng.put(format!("other{i}").as_bytes(), b"x").unwrap();
+            eng.flush().unwrap();
+        }
+        eng.compact().unwrap();
+        assert_eq!(eng.level_table_counts()[2..].iter().sum::<usize>(), 0);
+        assert_eq!(entries(&eng), 200 + opts.compaction.l0_trigger as u64);
+        let version = eng.current();
+        assert!(version
+            .sstables
+            .iter()
+            .all(|(_, _, r)| r.range_tombstones().is_empty()));
+        assert_eq!(eng.get(b"key0099").unwrap(), Some(b"v1599".to_vec()));
+        assert_eq!(eng.get(b"key0250").unwrap(), None);
+        assert_eq!(eng.scan(b"key", b"kez").count(), 200);
+        drop(eng);
+
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        assert_eq!(eng.get(b"key0399").unwrap(), None);
+        assert_eq!(eng.scan(b"key", b"kez").count(), 200);
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn readers_never_see_half_a_batch() {
+        let dir = temp_dir("batch-visibility");
+        let opts = EngineOptions {
+            memtable_max_bytes: 4096,
+            wal_sync: WalSyncPolicy::Never,
+            ..EngineOptions::default()
+        };
+        let eng = LsmEngine::open(&dir, opts).unwrap();
+        let writer = {
+            let eng = eng.clone();
+            std::thread::spawn(move || {
+                for n in 0..500u32 {
+                    let mut batch = WriteBatch::new();
+                    batch.put(b"a", &n.to_be_bytes());
+                    batch.put(b"b", &n.to_be_bytes());
+                    eng.write(batch).unwrap();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let values: Vec<_> = eng.scan(b"a", b"c").map(|r| r.unwrap().1).collect();
+            assert!(values.is_empty() || (values.len() == 2 && values[0] == values[1]));
+        }
+        writer.join().unwrap();
+        drop(eng);
+        let _ = fs::remove_dir_all(&dir);
     }
 }
@@ -4,11 +4,11 @@ use zynk::engine::crdt::ElementId;
 use zynk::engine::kv::LsmEngine;
 
 fn main() {
-    let mut engine = LsmEngine::new_with_manifest("data", 64 * 1024, 8 * 1024).expect("engine");
+    let engine = LsmEngine::new_with_manifest("data", 64 * 1024, 8 * 1024).expect("engine");
 
     let mut ih = InputHandler::with_history_file(PathBuf::from("data/history")).expect("input");
 
-    println!("Zynk LSM KV. Commands: put/get/del/flush/exit");
+    println!("Zynk LSM KV. Commands: put/get/del/scan/flush/compact/exit");
 
     while let Ok(line) = ih.readline("zynk> ") {
         let line = line.trim();
@@ -82,6 +82,32 @@ fn main() {
                 }
             }
 
+            "scan" => {
+                let mut parts = line.split_whitespace();
+                parts.next();
+                let rows = match (parts.next(), parts.next()) {
+                    (Some(start), Some(end)) => engine.scan(start.as_bytes(), end.as_bytes()),
+                    (Some(prefix), None) => engine.prefix_scan(prefix.as_bytes()),
+                    _ => {
+                        println!("usage: scan <prefix> | scan <start> <end>");
+                        continue;
+                    }
+                };
+                for row in rows {
+                    match row {
+                        Ok((k, v)) => println!(
+                            "{} => {}",
+                            String::from_utf8_lossy(&k),
+                            String::from_utf8_lossy(&v)
+                        ),
+                        Err(e) => {
+                            println!("error: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+
             "flush" => {
                 let mut parts = line.split_whitespace();
                 parts.next();
@@ -92,6 +118,15 @@ fn main() {
                 }
             }
 
+            "compact" => {
+                if let Err(e) = engine.compact() {
+                    println!("error: {e}");
+                } else {
+                    let counts = engine.level_table_counts();
+                    println!("compacted (tables per level: {counts:?})");
+                }
+            }
+
             "gput" => {
                 let mut parts = line.splitn(3, ' ');
                 parts.next();
@@ -181,9 +216,9 @@ fn main() {
                     }
                 };
                 let id = engine.next_element_id();
-                println!("Generated actor id: {}", engine.actor_id);
+                println!("Generated actor id: {}", engine.actor_id());
                 println!("Generated counter id: {}", id.counter);
-                if let Err(e) = engine.rga_insert_after(k, None, v, engine.actor_id, id.counter) {
+                if let Err(e) = engine.rga_insert_after(k, None, v, engine.actor_id(), id.counter) {
                     println!("error: {e}");
                 } else {
                     println!("OK (id = actor:{} counter:{})", id.actor, id.counter);
@@ -251,7 +286,8 @@ fn main() {
                 });
                 let id = engine.next_element_id();
 
-                if let Err(e) = engine.rga_insert_after(k, prev, value, engine.actor_id, id.counter)
+                if let Err(e) =
+                    engine.rga_insert_after(k, prev, value, engine.actor_id(), id.counter)
                 {
                     println!("error: {e}");
                 } else {
@@ -0,0 +1,512 @@
+use crate::storage::manifest::fsync_dir;
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::merge::{EntryIter, MergingIter, VersionGc};
+use crate::storage::sstable::{
+    builder::SsTableBuilder,
+    iter::SsTableIter,
+    range_del::{self, RangeTombstone},
+    reader::SsTableReader,
+    table_path, table_tmp_path, TableId, TableMeta, TableOptions,
+};
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+
+/// Size targets for leveled compaction.
+///
+/// Level 0 holds freshly flushed, possibly overlapping tables and is compacted
+/// by count. Every deeper level is a sorted run of non-overlapping tables
+/// whose total size is capped at `level1_max_bytes * level_multiplier^(n-1)`.
+#[derive(Clone, Debug)]
+pub struct CompactionOptions {
+    pub l0_trigger: usize,
+    pub level1_max_bytes: u64,
+    pub level_multiplier: u64,
+    pub max_levels: usize,
+    pub target_table_bytes: u64,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        Self {
+            l0_trigger: 4,
+            level1_max_bytes: 1024 * 1024,
+            level_multiplier: 10,
+            max_levels: 7,
+            target_table_bytes: 256 * 1024,
+        }
+    }
+}
+
+impl CompactionOptions {
+    pub fn level_max_bytes(&self, level: usize) -> u64 {
+        let mut max = self.level1_max_bytes;
+        for _ in 1..level {
+            max = max.saturating_mul(self.level_multiplier);
+        }
+        max
+    }
+}
+
+/// A set of input tables to merge into `output_level`.
+#[derive(Clone, Debug)]
+pub struct CompactionTask {
+    /// Inputs ordered newest first; on duplicate versions the earliest wins.
+    pub inputs: Vec<TableMeta>,
+    pub output_level: usize,
+    /// True when no deeper level overlaps the inputs, so a tombstone has
+    /// nothing left to shadow and can be discarded.
+    pub drop_tombstones: bool,
+    /// Oldest sequence number a live snapshot may read at; older versions
+    /// are kept only where such a snapshot could still see them.
+    pub smallest_snapshot: SeqNo,
+}
+
+/// Picks the most urgent compaction, if any level is over its target.
+pub fn pick_compaction(
+    tables: &[TableMeta],
+    opts: &CompactionOptions,
+    smallest_snapshot: SeqNo,
+) -> Option<CompactionTask> {
+    let last_level = opts.max_levels.saturating_sub(1);
+    let mut best: Option<(f64, usize)> = None;
+    for level in 0..last_level {
+        let score = if level == 0 {
+            let count = tables.iter().filter(|t| t.level == 0).count();
+            count as f64 / opts.l0_trigger.max(1) as f64
+        } else {
+            let bytes: u64 = tables
+                .iter()
+                .filter(|t| t.level == level)
+                .map(|t| t.file_len)
+                .sum();
+            bytes as f64 / opts.level_max_bytes(level) as f64
+        };
+        if score >= 1.0 && best.is_none_or(|(s, _)| score > s) {
+            best = Some((score, level));
+        }
+    }
+    let (_, level) = best?;
+
+    let mut inputs: Vec<TableMeta> = if level == 0 {
+        let mut l0: Vec<TableMeta> = tables.iter().filter(|t| t.level == 0).cloned().collect();
+        l0.sort_by_key(|t| std::cmp::Reverse(t.id));
+        l0
+    } else {
+        // Push down the table that rewrites the fewest next-level bytes per
+        // byte it moves, oldest first on ties.
+        let overlap = |t: &TableMeta| -> u128 {
+            tables
+                .iter()
+                .filter(|n| n.level == level + 1 && n.overlaps(&t.smallest, &t.largest))
+                .map(|n| n.file_len as u128)
+                .sum()
+        };
+        let chosen = tables.iter().filter(|t| t.level == level).min_by(|a, b| {
+            let ra = overlap(a) * b.file_len.max(1) as u128;
+            let rb = overlap(b) * a.file_len.max(1) as u128;
+            ra.cmp(&rb).then(a.id.cmp(&b.id))
+        })?;
+        vec![chosen.clone()]
+    };
+    let smallest = inputs.iter().map(|t| t.smallest.clone()).min()?;
+    let largest = inputs.iter().map(|t| t.largest.clone()).max()?;
+
+    let output_level = level + 1;
+    let mut next: Vec<TableMeta> = tables
+        .iter()
+        .filter(|t| t.level == output_level && t.overlaps(&smallest, &largest))
+        .cloned()
+        .collect();
+    next.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+    inputs.extend(next);
+
+    let smallest = inputs.iter().map(|t| t.smallest.clone()).min()?;
+    let largest = inputs.iter().map(|t| t.largest.clone()).max()?;
+    let drop_tombstones = !tables
+        .iter()
+        .any(|t| t.level > output_level && t.overlaps(&smallest, &largest));
+
+    Some(CompactionTask {
+        inputs,
+        output_level,
+        drop_tombstones,
+        smallest_snapshot,
+    })
+}
+
+struct OutputTable {
+    id: TableId,
+    builder: SsTableBuilder,
+    /// Split key the table starts at; `None` for the first output.
+    lower: Option<Vec<u8>>,
+    /// First and last key added, if any.
+    points: Option<(Vec<u8>, Vec<u8>)>,
+    bytes: u64,
+}
+
+impl OutputTable {
+    fn new(
+        sst_dir: &Path,
+        table_opts: &TableOptions,
+        next_table_id: &AtomicU64,
+        outputs: &mut Vec<(TableId, Option<TableMeta>)>,
+        lower: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        let id = next_table_id.fetch_add(1, Ordering::SeqCst);
+        outputs.push((id, None));
+        Ok(Self {
+            id,
+            builder: SsTableBuilder::new(&table_tmp_path(sst_dir, id), id, table_opts)?,
+            lower,
+            points: None,
+            bytes: 0,
+        })
+    }
+}
+
+/// Merges the task's inputs into new tables under `sst_dir`, splitting the
+/// output every `target_table_bytes` at a key boundary. Returns the metadata of the finished,
+/// renamed tables; the caller is responsible for recording them.
+pub fn run_compaction(
+    task: &CompactionTask,
+    sst_dir: &Path,
+    table_opts: &TableOptions,
+    target_table_bytes: u64,
+    next_table_id: &AtomicU64,
+) -> Result<Vec<TableMeta>> {
+    let mut outputs = Vec::new();
+    let res = merge_inputs(
+        task,
+        sst_dir,
+        table_opts,
+        target_table_bytes,
+        next_table_id,
+        &mut outputs,
+    );
+    if res.is_err() {
+        for (id, _) in &outputs {
+            let _ = fs::remove_file(table_tmp_path(sst_dir, *id));
+            let _ = fs::remove_file(table_path(sst_dir, *id));
+        }
+    }
+    res?;
+    Ok(outputs.into_iter().filter_map(|(_, meta)| meta).collect())
+}
+
+fn merge_inputs(
+    task: &CompactionTask,
+    sst_dir: &Path,
+    table_opts: &TableOptions,
+    target_table_bytes: u64,
+    next_table_id: &AtomicU64,
+    outputs: &mut Vec<(TableId, Option<TableMeta>)>,
+) -> Result<()> {
+    let mut readers = Vec::with_capacity(task.inputs.len());
+    for input in &task.inputs {
+        readers.push(SsTableReader::open(&table_path(sst_dir, input.id))?);
+    }
+    let sources: Vec<EntryIter<'_>> = readers
+        .iter()
+        .map(|r| Box::new(SsTableIter::new_seek(r, None)) as EntryIter<'_>)
+        .collect();
+
+    let tombstones: Vec<RangeTombstone> = readers
+        .iter()
+        .flat_map(|r| r.range_tombstones().iter().cloned())
+        .collect();
+    // Like point tombstones, a range tombstone every snapshot sees has
+    // nothing left to hide once no deeper level overlaps the inputs.
+    let kept: Vec<RangeTombstone> = tombstones
+        .iter()
+        .filter(|t| !(task.drop_tombstones && t.seq <= task.smallest_snapshot))
+        .cloned()
+        .collect();
+
+    let mut gc = VersionGc::new(task.smallest_snapshot, task.drop_tombstones)
+        .with_range_tombstones(tombstones);
+    let mut out: Option<OutputTable> = None;
+    let mut lower = None;
+    for item in MergingIter::new(sources) {
+        let (key, seq, entry) = item?;
+        if !gc.keep(&key, seq, &entry) {
+            continue;
+        }
+
+        // Versions of one key never straddle two tables of a sorted level.
+        if out.as_ref().is_some_and(|t| {
+            t.bytes >= target_table_bytes && t.points.as_ref().is_some_and(|(_, l)| *l != key)
+        }) {
+            let table = out.take().unwrap();
+            let meta = finish_output(table, sst_dir, task.output_level, &kept, Some(&key))?;
+            outputs.last_mut().unwrap().1 = Some(meta);
+            lower = Some(key.clone());
+        }
+        let table = match out.as_mut() {
+            Some(t) => t,
+            None => out.insert(OutputTable::new(
+                sst_dir,
+                table_opts,
+                next_table_id,
+                outputs,
+                lower.take(),
+            )?),
+        };
+        match &entry {
+            Entry::Put(v) => {
+                table.builder.add_put(&key, seq, v)?;
+                table.bytes += (key.len() + v.len()) as u64;
+            }
+            Entry::Delete => {
+                table.builder.add_delete(&key, seq)?;
+                table.bytes += key.len() as u64;
+            }
+        }
+        match &mut table.points {
+            Some((_, largest)) => *largest = key,
+            None => table.points = Some((key.clone(), key)),
+        }
+    }
+    if out.is_none() && !kept.is_empty() {
+        // Only range tombstones survived; they still need a table.
+        out = Some(OutputTable::new(
+            sst_dir,
+            table_opts,
+            next_table_id,
+            outputs,
+            None,
+        )?);
+    }
+    if let Some(table) = out.take() {
+        let meta = finish_output(table, sst_dir, task.output_level, &kept, None)?;
+        outputs.last_mut().unwrap().1 = Some(meta);
+    }
+    Ok(())
+}
+
+/// Finishes `table`, giving it the part of each range tombstone between its
+/// own split key and `upper`, the next table's, so the outputs of a sorted
+/// level stay apart.
+fn finish_output(
+    mut table: OutputTable,
+    sst_dir: &Path,
+    level: usize,
+    tombstones: &[RangeTombstone],
+    upper: Option<&[u8]>,
+) -> Result<TableMeta> {
+    let fragments: Vec<RangeTombstone> = tombstones
+        .iter()
+        .filter_map(|t| t.clip(table.lower.as_deref(), upper))
+        .collect();
+    let (smallest, largest) = range_del::key_bounds(table.points, &fragments);
+    for fragment in fragments {
+        table.builder.add_range_tombstone(fragment);
+    }
+    let tmp = table_tmp_path(sst_dir, table.id);
+    let final_path = table_path(sst_dir, table.id);
+    let num_entries = table.builder.num_entries();
+    let num_deletions = table.builder.num_deletions();
+    table.builder.finish()?;
+    fs::rename(&tmp, &final_path)?;
+    fsync_dir(&final_path)?;
+    Ok(TableMeta {
+        id: table.id,
+        level,
+        smallest,
+        largest,
+        file_len: fs::metadata(&final_path)?.len(),
+        num_entries,
+        num_deletions,
+    })
+}
+
+pub type CompactionResult = (CompactionTask, Result<Vec<TableMeta>>);
+
+/// Runs compaction tasks one at a time on a background thread.
+///
+/// The worker only writes new tables; installing them in the manifest and
+/// deleting the inputs is left to the engine, which polls for results.
+pub struct Compactor {
+    tx: Option<Sender<CompactionTask>>,
+    /// Only touched through `&mut self`; the mutex just makes the handle
+    /// `Sync` so the engine can be shared between threads.
+    rx: Mutex<Receiver<CompactionResult>>,
+    handle: Option<JoinHandle<()>>,
+    busy: bool,
+}
+
+impl Compactor {
+    pub fn spawn(
+        sst_dir: PathBuf,
+        table_opts: TableOptions,
+        target_table_bytes: u64,
+        next_table_id: Arc<AtomicU64>,
+    ) -> Self {
+        let (task_tx, task_rx) = mpsc::channel::<CompactionTask>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("zynk-compaction".to_string())
+            .spawn(move || {
+                for task in task_rx {
+                    let res = run_compaction(
+                        &task,
+                        &sst_dir,
+                        &table_opts,
+                        target_table_bytes,
+                        &next_table_id,
+                    );
+                    if done_tx.send((task, res)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("spawn compaction thread");
+        Self {
+            tx: Some(task_tx),
+            rx: Mutex::new(done_rx),
+            handle: Some(handle),
+            busy: false,
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    pub fn submit(&mut self, task: CompactionTask) {
+        if let Some(tx) = &self.tx {
+            if tx.send(task).is_ok() {
+                self.busy = true;
+            }
+        }
+    }
+
+    /// Returns the finished task, if the worker has one ready.
+    pub fn try_finished(&mut self) -> Option<CompactionResult> {
+        if !self.busy {
+            return None;
+        }
+        let res = self.results().try_recv().ok()?;
+        self.busy = false;
+        Some(res)
+    }
+
+    /// Blocks until the in-flight task, if any, has finished.
+    pub fn wait_finished(&mut self) -> Option<CompactionResult> {
+        if !self.busy {
+            return None;
+        }
+        self.busy = false;
+        self.results().recv().ok()
+    }
+
+    fn results(&mut self) -> &Receiver<CompactionResult> {
+        self.rx.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(id: TableId, level: usize, range: (&str, &str), file_len: u64) -> TableMeta {
+        TableMeta {
+            id,
+            level,
+            smallest: range.0.as_bytes().to_vec(),
+            largest: range.1.as_bytes().to_vec(),
+            file_len,
+            ..TableMeta::default()
+        }
+    }
+
+    #[test]
+    fn picks_the_level_table_with_the_least_overlap_per_byte() {
+        let opts = CompactionOptions {
+            level1_max_bytes: 1000,
+            ..CompactionOptions::default()
+        };
+        let tables = vec![
+            table(1, 1, ("a", "f"), 400),
+            table(2, 1, ("g", "m"), 400),
+            table(3, 1, ("n", "z"), 400),
+            table(4, 2, ("a", "c"), 5000),
+            table(5, 2, ("h", "i"), 100),
+            table(6, 2, ("o", "p"), 900),
+        ];
+        let task = pick_compaction(&tables, &opts, 0).unwrap();
+        assert_eq!(task.output_level, 2);
+        let ids: Vec<TableId> = task.inputs.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![2, 5]);
+        assert!(task.drop_tombstones);
+
+        let l0_only = vec![table(7, 0, ("a", "b"), 10)];
+        assert!(pick_compaction(&l0_only, &opts, 0).is_none());
+    }
+
+    #[test]
+    fn range_tombstones_are_split_between_output_tables() {
+        let dir =
+            std::env::temp_dir().join(format!("zynk-compaction-range-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let opts = TableOptions::default();
+        let mut builder = SsTableBuilder::new(&table_tmp_path(&dir, 1), 1, &opts).unwrap();
+        for i in 0..100u32 {
+            builder
+                .add_put(format!("k{i:03}").as_bytes(), 10, &[0; 64])
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        fs::rename(table_tmp_path(&dir, 1), table_path(&dir, 1)).unwrap();
+        let mut builder = SsTableBuilder::new(&table_tmp_path(&dir, 2), 2, &opts).unwrap();
+        builder.add_put(b"k050", 30, b"new").unwrap();
+        builder.add_range_tombstone(RangeTombstone {
+            start: b"k".to_vec(),
+            end: b"l".to_vec(),
+            seq: 20,
+        });
+        builder.finish().unwrap();
+        fs::rename(table_tmp_path(&dir, 2), table_path(&dir, 2)).unwrap();
+
+        let task = CompactionTask {
+            inputs: vec![table(2, 0, ("k", "l"), 0), table(1, 1, ("k000", "k099"), 0)],
+            output_level: 1,
+            drop_tombstones: false,
+            smallest_snapshot: 15,
+        };
+        let next_id = AtomicU64::new(3);
+        let outputs = run_compaction(&task, &dir, &opts, 1024, &next_id).unwrap();
+        assert!(outputs.len() > 2);
+        assert_eq!(outputs[0].smallest, b"k");
+        assert_eq!(outputs.last().unwrap().largest, b"l");
+        for (meta, next) in outputs.iter().zip(outputs.iter().skip(1)) {
+            assert!(meta.largest <= next.smallest);
+        }
+        let mut fragments = Vec::new();
+        for meta in &outputs {
+            let reader = SsTableReader::open(&table_path(&dir, meta.id)).unwrap();
+            fragments.extend(reader.range_tombstones().iter().cloned());
+        }
+        assert_eq!(fragments.len(), outputs.len());
+        assert!(fragments.windows(2).all(|w| w[0].end == w[1].start));
+        assert_eq!(
+            (&fragments[0].start[..], &fragments.last().unwrap().end[..]),
+            (&b"k"[..], &b"l"[..])
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Exclusive advisory lock on a data directory's `LOCK` file, held for as
+/// long as the value lives.
+///
+/// The lock belongs to the open file, so the kernel releases it when the
+/// process exits, however it exits; a stale `LOCK` file left behind is
+/// harmless. Read-only opens never take it.
+pub struct DirLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl DirLock {
+    /// Locks `data_dir`, failing with `ErrorKind::WouldBlock` if another
+    /// engine, in this process or another, already holds it.
+    pub fn acquire(data_dir: &Path) -> Result<Self> {
+        let path = lock_path(data_dir);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        try_lock_exclusive(&file).map_err(|e| {
+            if e.kind() == ErrorKind::WouldBlock {
+                Error::new(
+                    ErrorKind::WouldBlock,
+                    format!(
+                        "{} is locked by another engine; is another zynk process using it?",
+                        data_dir.display()
+                    ),
+                )
+            } else {
+                e
+            }
+        })?;
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn lock_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("LOCK")
+}
+
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: flock only reads the descriptor, which `file` keeps open.
+    let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock_exclusive(_file: &File) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_fails_until_the_first_is_dropped() {
+        let dir = std::env::temp_dir().join(format!("zynk-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lock = DirLock::acquire(&dir).unwrap();
+        assert_eq!(lock.path(), lock_path(&dir));
+        let err = DirLock::acquire(&dir).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        drop(lock);
+        DirLock::acquire(&dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
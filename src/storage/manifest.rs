@@ -1,14 +1,186 @@
 use std::{
     fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Result, Write},
+    io::{BufWriter, Error, ErrorKind, Read, Result, Write},
     path::{Path, PathBuf},
 };
 
-use crate::storage::sstable::TableId;
+use crate::storage::memtable::SeqNo;
+use crate::storage::sstable::{TableId, TableMeta};
 
+/// First bytes of every binary manifest; text manifests predate it.
+const MANIFEST_MAGIC: &[u8; 8] = b"ZYNKMANI";
+/// Version 1: CRC-framed binary edits.
+const MANIFEST_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4;
+/// Header written before every record: payload length followed by its crc32.
+const RECORD_HEADER_SIZE: usize = 4 + 4;
+
+/// Table addition carrying only its id and level; no longer written.
+const TAG_ADD: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_LAST_SEQ: u8 = 3;
+const TAG_ADD_TABLE: u8 = 4;
+const TAG_DB_ID: u8 = 5;
+
+/// Live state rebuilt from a manifest.
+#[derive(Clone, Debug, Default)]
+pub struct ManifestState {
+    /// Live tables in the order they were added. Tables added by manifests
+    /// that predate table metadata only carry their id and level, and have a
+    /// `file_len` of 0.
+    pub tables: Vec<TableMeta>,
+    /// Highest sequence number covered by a flushed table.
+    pub last_seq: SeqNo,
+    /// Unique id of the database, assigned when it is first opened.
+    pub db_id: Option<u128>,
+}
+
+/// One atomic change to the table set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ManifestEdit {
+    pub added: Vec<TableMeta>,
+    pub removed: Vec<TableId>,
+    pub last_seq: Option<SeqNo>,
+    pub db_id: Option<u128>,
+}
+
+impl ManifestEdit {
+    /// Payload layout: a sequence of tagged fields,
+    /// `add_table id u64 | level u32 | file_len u64 | num_entries u64 |
+    /// num_deletions u64 | smallest_len u32 | smallest | largest_len u32 |
+    /// largest`, `remove id u64`, `last_seq seq u64` and `db_id id u128`.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for meta in &self.added {
+            out.push(TAG_ADD_TABLE);
+            out.extend_from_slice(&meta.id.to_le_bytes());
+            out.extend_from_slice(&(meta.level as u32).to_le_bytes());
+            out.extend_from_slice(&meta.file_len.to_le_bytes());
+            out.extend_from_slice(&meta.num_entries.to_le_bytes());
+            out.extend_from_slice(&meta.num_deletions.to_le_bytes());
+            for key in [&meta.smallest, &meta.largest] {
+                out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                out.extend_from_slice(key);
+            }
+        }
+        for id in &self.removed {
+            out.push(TAG_REMOVE);
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        if let Some(seq) = self.last_seq {
+            out.push(TAG_LAST_SEQ);
+            out.extend_from_slice(&seq.to_le_bytes());
+        }
+        if let Some(db_id) = self.db_id {
+            out.push(TAG_DB_ID);
+            out.extend_from_slice(&db_id.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let mut edit = Self::default();
+        let mut r = PayloadReader {
+            buf: payload,
+            pos: 0,
+        };
+        while r.pos < payload.len() {
+            match r.bytes(1)?[0] {
+                TAG_ADD => edit.added.push(TableMeta {
+                    id: r.u64()?,
+                    level: r.u32()? as usize,
+                    ..TableMeta::default()
+                }),
+                TAG_ADD_TABLE => {
+                    let id = r.u64()?;
+                    let level = r.u32()? as usize;
+                    let file_len = r.u64()?;
+                    let num_entries = r.u64()?;
+                    let num_deletions = r.u64()?;
+                    let len = r.u32()? as usize;
+                    let smallest = r.bytes(len)?.to_vec();
+                    let len = r.u32()? as usize;
+                    let largest = r.bytes(len)?.to_vec();
+                    edit.added.push(TableMeta {
+                        id,
+                        level,
+                        smallest,
+                        largest,
+                        file_len,
+                        num_entries,
+                        num_deletions,
+                    });
+                }
+                TAG_REMOVE => edit.removed.push(r.u64()?),
+                TAG_LAST_SEQ => edit.last_seq = Some(r.u64()?),
+                TAG_DB_ID => {
+                    edit.db_id = Some(u128::from_le_bytes(r.bytes(16)?.try_into().unwrap()))
+                }
+                tag => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown manifest edit tag {tag}"),
+                    ))
+                }
+            }
+        }
+        Ok(edit)
+    }
+}
+
+struct PayloadReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "short manifest edit"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+impl ManifestState {
+    /// Removals apply before additions, so an edit can move a table or
+    /// replace its metadata.
+    fn apply(&mut self, edit: &ManifestEdit) {
+        self.tables.retain(|t| !edit.removed.contains(&t.id));
+        self.tables.extend_from_slice(&edit.added);
+        if let Some(seq) = edit.last_seq {
+            self.last_seq = self.last_seq.max(seq);
+        }
+        if edit.db_id.is_some() {
+            self.db_id = edit.db_id;
+        }
+    }
+}
+
+/// Append-only log of table set edits.
+///
+/// The file starts with a magic and version, followed by one record per
+/// edit: `len u32 | crc32(payload) u32 | payload`. The manifest also keeps
+/// the live state those edits add up to, so it can be checkpointed into a
+/// fresh file that holds just that state.
 pub struct Manifest {
     writer: BufWriter<File>,
     path: PathBuf,
+    state: ManifestState,
+    bytes: u64,
+    /// Set when replay cut a torn tail off the file.
+    truncated: bool,
 }
 
 impl Manifest {
@@ -17,43 +189,171 @@ impl Manifest {
             .create(true)
             .append(true)
             .open(&path)?;
-        Ok(Self {
+        let bytes = file.metadata()?.len();
+        let mut manifest = Self {
             writer: BufWriter::new(file),
             path,
+            state: ManifestState::default(),
+            bytes,
+            truncated: false,
+        };
+        if bytes == 0 {
+            manifest.write_header()?;
+        }
+        Ok(manifest)
+    }
+
+    /// Bytes in the manifest file, including edits appended since opening.
+    pub fn len(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+
+    /// Whether `replay_manifest` truncated a torn tail. Tables written by
+    /// the edits it held may still be on disk.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// File name of this manifest, as stored in CURRENT.
+    pub fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn record_add_table(&mut self, meta: &TableMeta) -> Result<()> {
+        self.record(ManifestEdit {
+            added: vec![meta.clone()],
+            ..ManifestEdit::default()
         })
     }
 
-    pub fn record_add_table(&mut self, table_id: TableId) -> Result<()> {
-        writeln!(self.writer, "add {table_id}")?;
-        self.sync()
+    /// Records a freshly flushed level-0 table together with the highest
+    /// sequence number it covers.
+    pub fn record_flush(&mut self, meta: &TableMeta, last_seq: SeqNo) -> Result<()> {
+        self.record(ManifestEdit {
+            added: vec![meta.clone()],
+            last_seq: Some(last_seq),
+            ..ManifestEdit::default()
+        })
     }
 
     pub fn record_remove_table(&mut self, table_id: TableId) -> Result<()> {
-        writeln!(self.writer, "remove {table_id}")?;
+        self.record(ManifestEdit {
+            removed: vec![table_id],
+            ..ManifestEdit::default()
+        })
+    }
+
+    /// Records a set of table additions and removals as a single record, so
+    /// a compaction is either fully visible or not at all.
+    pub fn record_edit(&mut self, added: &[TableMeta], removed: &[TableId]) -> Result<()> {
+        self.record(ManifestEdit {
+            added: added.to_vec(),
+            removed: removed.to_vec(),
+            ..ManifestEdit::default()
+        })
+    }
+
+    /// Appends `edit` as one record and syncs it.
+    pub fn record(&mut self, edit: ManifestEdit) -> Result<()> {
+        self.append_record(&edit.encode())?;
+        self.sync()?;
+        self.state.apply(&edit);
+        Ok(())
+    }
+
+    /// Writes the live state to the next manifest file, points CURRENT at it
+    /// and deletes this one, continuing to append to the new file.
+    ///
+    /// Until CURRENT is replaced the old manifest stays authoritative, and
+    /// the old file is only removed afterwards; a crash at any step leaves a
+    /// stale manifest that `remove_stale_manifests` deletes on the next open.
+    pub fn checkpoint(&mut self, data_dir: &Path) -> Result<()> {
+        let number = manifest_number(&self.file_name()).unwrap_or(0) + 1;
+        let name = manifest_name(number);
+        let path = data_dir.join(&name);
+        let _ = fs::remove_file(&path);
+        let mut next = Manifest::new(path)?;
+        next.record(ManifestEdit {
+            added: self.state.tables.clone(),
+            removed: Vec::new(),
+            last_seq: Some(self.state.last_seq),
+            db_id: self.state.db_id,
+        })?;
+        fsync_dir(&next.path)?;
+
+        write_current_atomic(data_dir, &name)?;
+        let old = std::mem::replace(self, next);
+        let old_path = old.path.clone();
+        drop(old);
+        fs::remove_file(&old_path)?;
+        fsync_dir(&old_path)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        self.writer.write_all(MANIFEST_MAGIC)?;
+        self.writer.write_all(&MANIFEST_VERSION.to_le_bytes())?;
+        self.bytes += HEADER_SIZE as u64;
         self.sync()
     }
 
-    pub fn replay_manifest(&mut self) -> Result<Vec<u64>> {
-        let file = std::fs::File::open(&self.path)?;
-        let reader = BufReader::new(file);
-        let mut active = Vec::new();
+    fn append_record(&mut self, payload: &[u8]) -> Result<()> {
+        let crc = crc32fast::hash(payload);
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.write_all(payload)?;
+        self.bytes += (RECORD_HEADER_SIZE + payload.len()) as u64;
+        Ok(())
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let parts: Vec<_> = line.split_whitespace().collect();
-            match parts.as_slice() {
-                ["add", id] => {
-                    let id: u64 = id.parse().unwrap();
-                    active.push(id);
+    /// Rebuilds the live tables and last flushed sequence number.
+    ///
+    /// A short or corrupt final record is what a crash during an append
+    /// looks like: it was never acknowledged, so it is truncated away. A bad
+    /// record followed by more data is real corruption and is an error, as
+    /// is a length running past the end of the file with whole records
+    /// still after it.
+    /// Text manifests written before the binary format are replayed and
+    /// then checkpointed into a binary one.
+    pub fn replay_manifest(&mut self) -> Result<ManifestState> {
+        let mut bytes = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+        match decode_manifest(&bytes, &self.path)? {
+            Contents::Text(state) => {
+                self.state = state;
+                if let Some(dir) = self.path.parent().map(Path::to_path_buf) {
+                    self.checkpoint(&dir)?;
                 }
-                ["remove", id] => {
-                    let id: u64 = id.parse().unwrap();
-                    active.retain(|&x| x != id);
+            }
+            Contents::Binary { state, valid_len } => {
+                self.truncated = valid_len < bytes.len();
+                if valid_len < HEADER_SIZE {
+                    // Crashed while writing the header of a new manifest.
+                    self.truncate_to(0)?;
+                    self.write_header()?;
+                } else if valid_len < bytes.len() {
+                    self.truncate_to(valid_len as u64)?;
                 }
-                _ => {}
+                self.state = state;
             }
         }
-        Ok(active)
+        Ok(self.state.clone())
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_ref();
+        file.set_len(len)?;
+        file.sync_all()?;
+        self.bytes = len;
+        Ok(())
     }
 
     pub fn sync(&mut self) -> Result<()> {
@@ -63,6 +363,181 @@ impl Manifest {
     }
 }
 
+/// What a manifest file holds, decoded without modifying it.
+enum Contents {
+    /// A text manifest from before the binary format.
+    Text(ManifestState),
+    /// A binary manifest whose first `valid_len` bytes are the header and
+    /// whole records; anything after them is a torn tail.
+    Binary {
+        state: ManifestState,
+        valid_len: usize,
+    },
+}
+
+fn decode_manifest(bytes: &[u8], path: &Path) -> Result<Contents> {
+    let magic_len = bytes.len().min(MANIFEST_MAGIC.len());
+    if !bytes.is_empty() && bytes[..magic_len] != MANIFEST_MAGIC[..magic_len] {
+        return Ok(Contents::Text(replay_text(bytes)?));
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Ok(Contents::Binary {
+            state: ManifestState::default(),
+            valid_len: 0,
+        });
+    }
+    let version = u32::from_le_bytes(bytes[8..HEADER_SIZE].try_into().unwrap());
+    if version != MANIFEST_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported manifest version {version}"),
+        ));
+    }
+
+    let mut state = ManifestState::default();
+    let mut p = HEADER_SIZE;
+    while p < bytes.len() {
+        let end = p
+            .checked_add(RECORD_HEADER_SIZE)
+            .filter(|&h| h <= bytes.len())
+            .map(|h| h + u32::from_le_bytes(bytes[p..p + 4].try_into().unwrap()) as usize);
+        let Some(end) = end.filter(|&e| e <= bytes.len()) else {
+            // A torn append is the last thing in the file. If a whole record
+            // follows, the length field itself is damaged.
+            if whole_record_after(bytes, p) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("manifest {} corrupt at offset {p}", path.display()),
+                ));
+            }
+            break;
+        };
+        let crc_stored = u32::from_le_bytes(bytes[p + 4..p + 8].try_into().unwrap());
+        let payload = &bytes[p + RECORD_HEADER_SIZE..end];
+        if crc32fast::hash(payload) != crc_stored {
+            if end == bytes.len() {
+                break;
+            }
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("manifest {} corrupt at offset {p}", path.display()),
+            ));
+        }
+        state.apply(&ManifestEdit::decode(payload)?);
+        p = end;
+    }
+    Ok(Contents::Binary {
+        state,
+        valid_len: p,
+    })
+}
+
+/// Whether a whole, valid record starts anywhere after offset `p`.
+fn whole_record_after(bytes: &[u8], p: usize) -> bool {
+    (p + 1..bytes.len().saturating_sub(RECORD_HEADER_SIZE)).any(|q| {
+        let len = u32::from_le_bytes(bytes[q..q + 4].try_into().unwrap()) as usize;
+        let start = q + RECORD_HEADER_SIZE;
+        if len == 0 || len > bytes.len() - start {
+            return false;
+        }
+        let payload = &bytes[start..start + len];
+        let crc = u32::from_le_bytes(bytes[q + 4..q + 8].try_into().unwrap());
+        crc32fast::hash(payload) == crc && ManifestEdit::decode(payload).is_ok()
+    })
+}
+
+/// Reads the live state from the manifest CURRENT names without creating,
+/// truncating or converting anything, e.g. while another process owns the
+/// directory. A torn tail is ignored rather than removed.
+pub fn read_manifest_state(data_dir: &Path) -> Result<ManifestState> {
+    let name = fs::read_to_string(current_path(data_dir))?;
+    let path = data_dir.join(name.trim());
+    let bytes = fs::read(&path)?;
+    match decode_manifest(&bytes, &path)? {
+        Contents::Text(state) | Contents::Binary { state, .. } => Ok(state),
+    }
+}
+
+/// Replays a pre-binary text manifest: one edit per line made of
+/// `add <id> [level]`, `remove <id>` and `seq <n>` tokens. `add <id>` without
+/// a level predates compaction and means level 0.
+fn replay_text(bytes: &[u8]) -> Result<ManifestState> {
+    let bad = |line: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("bad manifest line {line:?}"),
+        )
+    };
+    let text = String::from_utf8_lossy(bytes);
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    // The final line is only complete if the file ends with a newline.
+    let complete = lines.len() - 1;
+    lines.truncate(complete);
+    let mut state = ManifestState::default();
+    for line in lines {
+        let mut edit = ManifestEdit::default();
+        let mut parts = line.split_whitespace().peekable();
+        while let Some(op) = parts.next() {
+            let mut num = || -> Result<u64> {
+                parts
+                    .next()
+                    .and_then(|p| p.parse().ok())
+                    .ok_or_else(|| bad(line))
+            };
+            match op {
+                "add" => {
+                    let id = num()?;
+                    let level = match parts.peek().map(|p| p.parse::<usize>()) {
+                        Some(Ok(level)) => {
+                            parts.next();
+                            level
+                        }
+                        _ => 0,
+                    };
+                    edit.added.push(TableMeta {
+                        id,
+                        level,
+                        ..TableMeta::default()
+                    });
+                }
+                "remove" => edit.removed.push(num()?),
+                "seq" => edit.last_seq = Some(num()?),
+                _ => return Err(bad(line)),
+            }
+        }
+        state.apply(&edit);
+    }
+    Ok(state)
+}
+
+pub fn manifest_name(number: u64) -> String {
+    format!("MANIFEST-{number:06}")
+}
+
+/// Number of a `MANIFEST-<n>` file name.
+pub fn manifest_number(name: &str) -> Option<u64> {
+    name.strip_prefix("MANIFEST-")?.parse().ok()
+}
+
+/// Deletes every manifest other than the one CURRENT names, left behind by a
+/// checkpoint that crashed before or after switching CURRENT.
+pub fn remove_stale_manifests(data_dir: &Path, current_name: &str) -> Result<()> {
+    let mut removed = false;
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if manifest_number(&name).is_some() && name != current_name {
+            fs::remove_file(entry.path())?;
+            removed = true;
+        }
+    }
+    if removed {
+        fsync_dir(&current_path(data_dir))?;
+    }
+    Ok(())
+}
+
 pub fn current_path(data_dir: &Path) -> PathBuf {
     data_dir.join("CURRENT")
 }
@@ -102,7 +577,6 @@ pub fn read_current_or_init(data_dir: &Path, initial_manifest_name: &str) -> Res
     if current.exists() {
         let mut s = String::new();
         let mut f = File::open(&current)?;
-        use std::io::Read;
         f.read_to_string(&mut s)?;
         return Ok(s.trim().to_string());
     }
@@ -122,3 +596,137 @@ pub fn read_current_or_init(data_dir: &Path, initial_manifest_name: &str) -> Res
 pub fn open_manifest_append(data_dir: &Path, manifest_name: &str) -> Result<Manifest> {
     Manifest::new(data_dir.join(manifest_name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zynk-manifest-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn replay(path: &Path) -> Result<ManifestState> {
+        Manifest::new(path.to_path_buf())?.replay_manifest()
+    }
+
+    fn table(id: TableId, level: usize) -> TableMeta {
+        TableMeta {
+            id,
+            level,
+            smallest: format!("a{id}").into_bytes(),
+            largest: format!("z{id}").into_bytes(),
+            file_len: 1000 * id,
+            num_entries: 10 * id,
+            num_deletions: id,
+        }
+    }
+
+    fn ids(state: &ManifestState) -> Vec<(TableId, usize)> {
+        state.tables.iter().map(|t| (t.id, t.level)).collect()
+    }
+
+    #[test]
+    fn torn_tail_is_truncated_and_appends_continue() {
+        let dir = temp_dir("torn");
+        let path = dir.join(manifest_name(1));
+        let mut m = Manifest::new(path.clone()).unwrap();
+        m.record_flush(&table(1, 0), 10).unwrap();
+        m.record_edit(&[table(2, 1)], &[1]).unwrap();
+        let good_len = m.len();
+        drop(m);
+
+        // A half-written record: a full length header but a short payload.
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[64, 0, 0, 0, 1, 2, 3, 4, TAG_ADD, 9]).unwrap();
+        drop(f);
+
+        let mut m = Manifest::new(path.clone()).unwrap();
+        let state = m.replay_manifest().unwrap();
+        assert_eq!(state.tables, vec![table(2, 1)]);
+        assert_eq!(state.last_seq, 10);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+        m.record_flush(&table(3, 0), 20).unwrap();
+        drop(m);
+        let state = replay(&path).unwrap();
+        assert_eq!(state.tables, vec![table(2, 1), table(3, 0)]);
+        assert_eq!(state.last_seq, 20);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_an_error() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join(manifest_name(1));
+        let mut m = Manifest::new(path.clone()).unwrap();
+        m.record_flush(&table(1, 0), 10).unwrap();
+        m.record_flush(&table(2, 0), 20).unwrap();
+        drop(m);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + RECORD_HEADER_SIZE + 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let err = replay(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn damaged_length_before_the_tail_is_an_error() {
+        let dir = temp_dir("bad-length");
+        let path = dir.join(manifest_name(1));
+        let mut m = Manifest::new(path.clone()).unwrap();
+        m.record_flush(&table(1, 0), 10).unwrap();
+        m.record_flush(&table(2, 0), 20).unwrap();
+        m.record_flush(&table(3, 0), 30).unwrap();
+        drop(m);
+
+        // Point the first record's length past the end of the file.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 3] = 0x7f;
+        fs::write(&path, &bytes).unwrap();
+
+        let err = replay(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        write_current_atomic(&dir, &manifest_name(1)).unwrap();
+        assert!(read_manifest_state(&dir).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn text_manifest_is_converted_to_binary() {
+        let dir = temp_dir("text");
+        let name = manifest_name(1);
+        fs::write(
+            dir.join(&name),
+            "add 1\nadd 2 0 seq 7\nadd 3 1 remove 1 remove 2\nadd 4",
+        )
+        .unwrap();
+        write_current_atomic(&dir, &name).unwrap();
+
+        let mut m = open_manifest_append(&dir, &name).unwrap();
+        let state = m.replay_manifest().unwrap();
+        assert_eq!(ids(&state), vec![(3, 1)]);
+        assert_eq!(state.tables[0].file_len, 0);
+        assert_eq!(state.last_seq, 7);
+        assert_eq!(m.file_name(), manifest_name(2));
+        assert!(!dir.join(&name).exists());
+        drop(m);
+
+        let current = read_current_or_init(&dir, &name).unwrap();
+        assert_eq!(current, manifest_name(2));
+        assert!(fs::read(dir.join(&current))
+            .unwrap()
+            .starts_with(MANIFEST_MAGIC));
+        let state = replay(&dir.join(current)).unwrap();
+        assert_eq!(ids(&state), vec![(3, 1)]);
+        assert_eq!(state.last_seq, 7);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
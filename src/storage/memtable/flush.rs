@@ -1,39 +1,212 @@
-use super::table::{Entry, MemTable};
+use super::table::{Entry, MemTable, SeqNo};
+use crate::storage::manifest::fsync_dir;
+use crate::storage::merge::VersionGc;
 use crate::storage::sstable::builder::SsTableBuilder;
-use crate::storage::sstable::TableId;
-use std::path::Path;
+use crate::storage::sstable::range_del;
+use crate::storage::sstable::{table_path, table_tmp_path, TableId, TableMeta, TableOptions};
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
 
 pub struct FlushResult {
     pub id: TableId,
     pub smallest: Vec<u8>,
     pub largest: Vec<u8>,
     pub file_len: u64,
+    pub num_entries: u64,
+    pub num_deletions: u64,
 }
 
+/// Writes `mem` to SSTable `id`, dropping versions shadowed for every
+/// snapshot at or above `smallest_snapshot`. Tombstones, range tombstones
+/// included, are always kept since older tables may still hold the keys.
+/// Versions hidden by one of the memtable's range tombstones are dropped.
 pub fn flush_memtable_to_sstable(
-    mem: MemTable,
+    mem: &MemTable,
     tmp_path: &Path,
-    block_size: usize,
+    id: TableId,
+    opts: &TableOptions,
+    smallest_snapshot: SeqNo,
 ) -> std::io::Result<FlushResult> {
-    let mut builder = SsTableBuilder::new(tmp_path, block_size);
-    let mut smallest: Option<Vec<u8>> = None;
-    let mut largest: Option<Vec<u8>> = None;
-    for (k, v) in mem.iter() {
-        if smallest.is_none() {
-            smallest = Some(k.clone());
+    let mut builder = SsTableBuilder::new(tmp_path, id, opts)?;
+    let mut bounds: Option<(Vec<u8>, Vec<u8>)> = None;
+    let tombstones = mem.range_tombstones();
+    let mut gc = VersionGc::new(smallest_snapshot, false).with_range_tombstones(tombstones.clone());
+    for (k, seq, v) in mem.iter() {
+        if !gc.keep(&k, seq, &v) {
+            continue;
         }
-        largest = Some(k.clone());
-        match v {
-            Entry::Put(val) => builder.add_put(k, val),
-            Entry::Delete => builder.add_delete(k),
+        match &v {
+            Entry::Put(val) => builder.add_put(&k, seq, val)?,
+            Entry::Delete => builder.add_delete(&k, seq)?,
         }
+        match &mut bounds {
+            Some((_, largest)) => *largest = k,
+            None => bounds = Some((k.clone(), k)),
+        }
+    }
+    let (smallest, largest) = range_del::key_bounds(bounds, &tombstones);
+    for tombstone in tombstones {
+        builder.add_range_tombstone(tombstone);
     }
+    let (num_entries, num_deletions) = (builder.num_entries(), builder.num_deletions());
     let (id, _index_handle) = builder.finish()?;
     let meta = std::fs::metadata(tmp_path)?;
     Ok(FlushResult {
         id,
-        smallest: smallest.unwrap_or_default(),
-        largest: largest.unwrap_or_default(),
+        smallest,
+        largest,
         file_len: meta.len(),
+        num_entries,
+        num_deletions,
     })
 }
+
+/// Flushes `mem` into level-0 table `id` under `sst_dir` and renames it into
+/// place. The caller is responsible for recording it in the manifest.
+pub fn write_level0_table(
+    mem: &MemTable,
+    sst_dir: &Path,
+    opts: &TableOptions,
+    id: TableId,
+    smallest_snapshot: SeqNo,
+) -> Result<TableMeta> {
+    let tmp = table_tmp_path(sst_dir, id);
+    let final_path = table_path(sst_dir, id);
+    let res = match flush_memtable_to_sstable(mem, &tmp, id, opts, smallest_snapshot) {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    fs::rename(&tmp, &final_path)?;
+    fsync_dir(&final_path)?;
+    Ok(TableMeta {
+        id,
+        level: 0,
+        smallest: res.smallest,
+        largest: res.largest,
+        file_len: res.file_len,
+        num_entries: res.num_entries,
+        num_deletions: res.num_deletions,
+    })
+}
+
+/// A frozen memtable waiting to be written, with the WAL segment that can be
+/// deleted once its table is installed.
+pub struct FlushTask {
+    pub mem: Arc<MemTable>,
+    pub wal_path: PathBuf,
+    pub smallest_snapshot: SeqNo,
+}
+
+pub type FlushOutcome = (FlushTask, Result<TableMeta>);
+
+/// Writes frozen memtables to level-0 tables on a background thread, in the
+/// order they were submitted.
+///
+/// Like the `Compactor`, the worker only writes files; the engine installs
+/// finished tables in the manifest and retires the memtable and its WAL.
+pub struct Flusher {
+    tx: Option<Sender<FlushTask>>,
+    /// Behind a mutex only so that `Flusher` is `Sync`, as for `Compactor`.
+    rx: Mutex<Receiver<FlushOutcome>>,
+    handle: Option<JoinHandle<()>>,
+    in_flight: usize,
+}
+
+impl Flusher {
+    pub fn spawn(
+        sst_dir: PathBuf,
+        table_opts: TableOptions,
+        next_table_id: Arc<AtomicU64>,
+    ) -> Self {
+        let (task_tx, task_rx) = mpsc::channel::<FlushTask>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("zynk-flush".to_string())
+            .spawn(move || {
+                for task in task_rx {
+                    let id = next_table_id.fetch_add(1, Ordering::SeqCst);
+                    let res = write_level0_table(
+                        &task.mem,
+                        &sst_dir,
+                        &table_opts,
+                        id,
+                        task.smallest_snapshot,
+                    );
+                    if done_tx.send((task, res)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("spawn flush thread");
+        Self {
+            tx: Some(task_tx),
+            rx: Mutex::new(done_rx),
+            handle: Some(handle),
+            in_flight: 0,
+        }
+    }
+
+    /// Queues `task`; fails if the worker has stopped.
+    pub fn submit(&mut self, task: FlushTask) -> Result<()> {
+        match &self.tx {
+            Some(tx) if tx.send(task).is_ok() => {
+                self.in_flight += 1;
+                Ok(())
+            }
+            _ => Err(worker_stopped()),
+        }
+    }
+
+    /// Returns the oldest finished flush, if the worker has one ready.
+    /// Fails if the worker stopped with flushes still in flight.
+    pub fn try_finished(&mut self) -> Result<Option<FlushOutcome>> {
+        if self.in_flight == 0 {
+            return Ok(None);
+        }
+        match self.results().try_recv() {
+            Ok(res) => {
+                self.in_flight -= 1;
+                Ok(Some(res))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(worker_stopped()),
+        }
+    }
+
+    /// Blocks until the oldest in-flight flush, if any, has finished.
+    /// Fails if the worker stopped before finishing it.
+    pub fn wait_finished(&mut self) -> Result<Option<FlushOutcome>> {
+        if self.in_flight == 0 {
+            return Ok(None);
+        }
+        let res = self.results().recv().map_err(|_| worker_stopped())?;
+        self.in_flight -= 1;
+        Ok(Some(res))
+    }
+
+    fn results(&mut self) -> &Receiver<FlushOutcome> {
+        self.rx.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The worker only exits early by panicking, taking its queued tasks with it.
+fn worker_stopped() -> std::io::Error {
+    std::io::Error::other("flush thread stopped")
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -2,6 +2,6 @@ pub mod flush;
 pub mod set;
 pub mod table;
 
-pub use flush::{flush_memtable_to_sstable, FlushResult};
+pub use flush::{flush_memtable_to_sstable, write_level0_table, FlushResult, FlushTask, Flusher};
 pub use set::MemTableSet;
-pub use table::{Entry, MemTable};
+pub use table::{Entry, MemTable, MemTableIter, SeqNo, MAX_SEQ};
@@ -1,16 +1,26 @@
-use super::table::{Entry, MemTable};
+use super::table::{Entry, MemTable, SeqNo, MAX_SEQ};
+use std::collections::VecDeque;
+use std::sync::Arc;
 
+/// The active memtable plus the frozen ones still waiting to be flushed.
+///
+/// Frozen memtables are shared with the flusher through an `Arc` rather than
+/// copied, and dropped from the set once their table has been installed.
+/// The active one is shared too: cloning the set is cheap, and writes
+/// through any clone land in the same table until it is rotated out.
+#[derive(Clone)]
 pub struct MemTableSet {
-    active: MemTable,
-    immutables: Vec<MemTable>,
+    active: Arc<MemTable>,
+    /// Frozen memtables, oldest first.
+    immutables: VecDeque<Arc<MemTable>>,
     max_bytes: usize,
 }
 
 impl MemTableSet {
     pub fn with_capacity(max_bytes: usize) -> Self {
         Self {
-            active: MemTable::new(max_bytes),
-            immutables: Vec::new(),
+            active: Arc::new(MemTable::new(max_bytes)),
+            immutables: VecDeque::new(),
             max_bytes,
         }
     }
@@ -23,44 +33,113 @@ impl MemTableSet {
         self.immutables.len()
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Option<MemTable> {
-        self.active.put(key, value);
-        if self.active.over_threshold() {
-            return self.rotate();
-        }
-        None
+    /// Writes to the active memtable; true once it is due to be rotated.
+    pub fn put(&self, key: &[u8], seq: SeqNo, value: &[u8]) -> bool {
+        self.active.put(key, seq, value);
+        self.active.over_threshold()
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> Option<MemTable> {
-        self.active.delete(key);
-        if self.active.over_threshold() {
-            return self.rotate();
-        }
-        None
+    /// Like `put`, for a tombstone.
+    pub fn delete(&self, key: &[u8], seq: SeqNo) -> bool {
+        self.active.delete(key, seq);
+        self.active.over_threshold()
+    }
+
+    /// Like `put`, for a range tombstone over `[start, end)`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8], seq: SeqNo) -> bool {
+        self.active.delete_range(start, end, seq);
+        self.active.over_threshold()
     }
 
-    pub fn rotate(&mut self) -> Option<MemTable> {
+    /// Freezes the active memtable, returning a shared handle to it for the
+    /// flusher. Returns `None` when there is nothing to freeze.
+    pub fn rotate(&mut self) -> Option<Arc<MemTable>> {
         if self.active.is_empty() {
             return None;
         }
-        let frozen = std::mem::replace(&mut self.active, MemTable::new(self.max_bytes));
-        self.immutables.push(frozen);
-        self.immutables.last().cloned()
+        let frozen = std::mem::replace(&mut self.active, Arc::new(MemTable::new(self.max_bytes)));
+        self.immutables.push_back(frozen.clone());
+        Some(frozen)
     }
 
-    pub fn pop_immutable(&mut self) -> Option<MemTable> {
-        self.immutables.pop()
+    /// Adds an already frozen memtable as the newest one, e.g. a WAL segment
+    /// replayed by a read-only engine that will never flush it.
+    pub fn push_frozen(&mut self, mem: MemTable) {
+        self.immutables.push_back(Arc::new(mem));
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        if let Some(e) = self.active.get(key) {
-            return Some(e);
+    /// Drops `frozen` from the set once its table has been installed. Its
+    /// memory is freed when the flusher lets go of its handle too.
+    pub fn remove_immutable(&mut self, frozen: &Arc<MemTable>) -> bool {
+        match self.immutables.iter().position(|m| Arc::ptr_eq(m, frozen)) {
+            Some(i) => {
+                self.immutables.remove(i);
+                true
+            }
+            None => false,
         }
-        for mt in self.immutables.iter().rev() {
-            if let Some(e) = mt.get(key) {
-                return Some(e);
+    }
+
+    /// Bytes held by the active memtable and every frozen one.
+    pub fn total_bytes(&self) -> usize {
+        self.active.bytes_used()
+            + self
+                .immutables
+                .iter()
+                .map(|m| m.bytes_used())
+                .sum::<usize>()
+    }
+
+    /// The active memtable followed by the immutables, newest first.
+    pub fn tables(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        std::iter::once(&self.active).chain(self.immutables.iter().rev())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.get_at(key, MAX_SEQ)
+    }
+
+    /// Newest version of `key` visible at `seq`, searching newest tables first.
+    pub fn get_at(&self, key: &[u8], seq: SeqNo) -> Option<Entry> {
+        self.tables().find_map(|mt| mt.get_at(key, seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_shares_frozen_tables_and_frees_them_on_removal() {
+        let mut set = MemTableSet::with_capacity(64);
+        let mut frozen = None;
+        for seq in 1..=10u64 {
+            if set.put(format!("k{seq}").as_bytes(), seq, b"0123456789") {
+                frozen = set.rotate();
+                break;
             }
         }
-        None
+        let frozen = frozen.expect("memtable should rotate");
+        assert_eq!(Arc::strong_count(&frozen), 2);
+        assert_eq!(set.immutables_len(), 1);
+        assert_eq!(set.total_bytes(), frozen.bytes_used());
+        assert!(matches!(set.get(b"k1"), Some(Entry::Put(_))));
+
+        assert!(set.remove_immutable(&frozen));
+        assert_eq!(Arc::strong_count(&frozen), 1);
+        assert_eq!(set.total_bytes(), 0);
+        assert!(set.get(b"k1").is_none());
+    }
+
+    #[test]
+    fn clones_share_the_active_table_until_rotation() {
+        let mut set = MemTableSet::with_capacity(1024);
+        let reader = set.clone();
+        set.put(b"a", 1, b"1");
+        assert!(matches!(reader.get(b"a"), Some(Entry::Put(_))));
+        set.rotate();
+        set.put(b"b", 2, b"2");
+        assert!(reader.get(b"b").is_none());
+        assert!(matches!(set.get(b"a"), Some(Entry::Put(_))));
     }
 }
@@ -1,4 +1,16 @@
-use std::collections::BTreeMap;
+use crate::storage::sstable::range_del::{self, RangeTombstone};
+use std::alloc::{self, Layout};
+use std::cmp::Ordering as CmpOrdering;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+
+/// Sequence number stamped on every write; higher is newer.
+pub type SeqNo = u64;
+
+/// Largest sequence number, used to read the newest version of a key.
+pub const MAX_SEQ: SeqNo = u64::MAX;
 
 #[derive(Clone)]
 pub enum Entry {
@@ -6,86 +18,568 @@ pub enum Entry {
     Delete,
 }
 
-#[derive(Clone)]
+/// Tallest tower a node can get; with a branching factor of 4 this keeps
+/// searches logarithmic well past a million entries.
+const MAX_HEIGHT: usize = 12;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 0;
+
+/// In-memory write buffer holding every version of a key, ordered by key and
+/// then newest sequence number first.
+///
+/// Versions live in a skiplist whose nodes, keys and values are all carved
+/// out of an arena owned by the table, so `bytes_used` is the memory the
+/// entries really take. Inserts take `&self` and may run on many threads at
+/// once: the arena takes a short lock to hand out space, and the node is
+/// then linked in with compare-and-swap. Nodes are never unlinked or freed
+/// before the table is dropped, so readers walk the list without locking.
+///
+/// Range tombstones are kept beside the list rather than in it; point reads
+/// only take their lock once the table holds one.
 pub struct MemTable {
-    map: BTreeMap<Vec<u8>, Entry>,
-    bytes_used: usize,
+    head: NodeRef,
+    /// Current tallest tower in the list.
+    height: AtomicUsize,
+    arena: Arena,
+    len: AtomicUsize,
+    max_seq: AtomicU64,
     max_bytes: usize,
+    range_dels: RwLock<Vec<RangeTombstone>>,
+    range_del_bytes: AtomicUsize,
 }
 
+// SAFETY: nodes are written once before being published with release
+// stores and only read afterwards; all shared mutable state is atomic or
+// behind the arena's mutex.
+unsafe impl Send for MemTable {}
+unsafe impl Sync for MemTable {}
+
 impl MemTable {
     pub fn new(max_bytes: usize) -> Self {
         Self {
-            map: BTreeMap::new(),
-            bytes_used: 0,
+            head: NodeRef::alloc_head(),
+            height: AtomicUsize::new(1),
+            arena: Arena::new(max_bytes.clamp(4 * 1024, 1024 * 1024)),
+            len: AtomicUsize::new(0),
+            max_seq: AtomicU64::new(0),
             max_bytes,
+            range_dels: RwLock::new(Vec::new()),
+            range_del_bytes: AtomicUsize::new(0),
         }
     }
 
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.len.load(Ordering::Acquire)
     }
 
+    /// True when the table holds neither versions nor range tombstones.
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.len() == 0 && self.range_del_bytes.load(Ordering::Acquire) == 0
     }
 
+    /// Arena bytes taken by entries: nodes with their towers, keys and
+    /// values, alignment padding, and the unused ends of filled chunks; plus
+    /// the range tombstones and their keys.
     pub fn bytes_used(&self) -> usize {
-        self.bytes_used
+        self.arena.used() + self.range_del_bytes.load(Ordering::Acquire)
     }
 
     pub fn max_bytes(&self) -> usize {
         self.max_bytes
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8]) {
-        self.adjust_remove(key);
-        self.bytes_used += 1 + 4 + 4 + key.len() + value.len();
-        self.map.insert(key.to_vec(), Entry::Put(value.to_vec()));
+    /// Highest sequence number written to this memtable, 0 if empty.
+    pub fn max_seq(&self) -> SeqNo {
+        self.max_seq.load(Ordering::Acquire)
+    }
+
+    pub fn put(&self, key: &[u8], seq: SeqNo, value: &[u8]) {
+        self.insert(key, seq, KIND_PUT, value);
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
-        self.adjust_remove(key);
-        self.bytes_used += 1 + 4 + 4 + key.len();
-        self.map.insert(key.to_vec(), Entry::Delete);
+    pub fn delete(&self, key: &[u8], seq: SeqNo) {
+        self.insert(key, seq, KIND_DELETE, &[]);
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
-        self.map.get(key)
+    /// Deletes every key in `[start, end)` written before `seq`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8], seq: SeqNo) {
+        let tombstone = RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        };
+        let bytes = start.len() + end.len() + std::mem::size_of::<RangeTombstone>();
+        self.range_dels
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tombstone);
+        self.range_del_bytes.fetch_add(bytes, Ordering::AcqRel);
+        self.max_seq.fetch_max(seq, Ordering::AcqRel);
+    }
+
+    /// The table's range tombstones, oldest first.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        if self.range_del_bytes.load(Ordering::Acquire) == 0 {
+            return Vec::new();
+        }
+        self.range_dels
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Newest version of `key`.
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.get_at(key, MAX_SEQ)
+    }
+
+    /// Newest version of `key` with a sequence number `<= seq`, or a
+    /// tombstone if one of the table's range tombstones is newer.
+    pub fn get_at(&self, key: &[u8], seq: SeqNo) -> Option<Entry> {
+        let found = match self.seek(key, seq) {
+            Some(node) if node.key() == key => Some((node.header().seq, node.entry())),
+            _ => None,
+        };
+        if self.range_del_bytes.load(Ordering::Acquire) == 0 {
+            return found.map(|(_, entry)| entry);
+        }
+        let range_dels = self
+            .range_dels
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        match (range_del::newest_covering(&range_dels, key, seq), found) {
+            (Some(covered), Some((found_seq, _))) if covered > found_seq => Some(Entry::Delete),
+            (Some(_), None) => Some(Entry::Delete),
+            (_, found) => found.map(|(_, entry)| entry),
+        }
     }
 
     pub fn contains_key(&self, key: &[u8]) -> bool {
-        self.map.contains_key(key)
+        self.get(key).is_some()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
-        self.map.iter()
+    /// Every version in key order, newest first within a key.
+    pub fn iter(&self) -> MemTableIter<&Self> {
+        MemTableIter::new(self, &[])
     }
 
-    pub fn smallest_key(&self) -> Option<&[u8]> {
-        self.map.keys().next().map(|k| k.as_slice())
+    /// Versions with keys `>= start`, in the same order as `iter`.
+    pub fn range_from(&self, start: &[u8]) -> MemTableIter<&Self> {
+        MemTableIter::new(self, start)
     }
 
-    pub fn largest_key(&self) -> Option<&[u8]> {
-        self.map.keys().next_back().map(|k| k.as_slice())
+    pub fn smallest_key(&self) -> Option<Vec<u8>> {
+        self.head.next(0).map(|node| node.key().to_vec())
+    }
+
+    pub fn largest_key(&self) -> Option<Vec<u8>> {
+        let mut x = self.head;
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            while let Some(next) = x.next(level) {
+                x = next;
+            }
+        }
+        (x != self.head).then(|| x.key().to_vec())
     }
 
     pub fn over_threshold(&self) -> bool {
-        self.bytes_used >= self.max_bytes
+        self.bytes_used() >= self.max_bytes
     }
 
-    fn adjust_remove(&mut self, key: &[u8]) {
-        if let Some(prev) = self.map.get(key) {
-            match prev {
-                Entry::Put(v) => {
-                    self.bytes_used = self
-                        .bytes_used
-                        .saturating_sub(1 + 4 + 4 + key.len() + v.len());
-                }
-                Entry::Delete => {
-                    self.bytes_used = self.bytes_used.saturating_sub(1 + 4 + 4 + key.len());
+    /// First node at or after `(key, seq)` in list order.
+    fn seek(&self, key: &[u8], seq: SeqNo) -> Option<NodeRef> {
+        let mut x = self.head;
+        let mut level = self.height.load(Ordering::Acquire) - 1;
+        loop {
+            match x.next(level) {
+                Some(next) if next.cmp_to(key, seq) == CmpOrdering::Less => x = next,
+                found if level == 0 => return found,
+                _ => level -= 1,
+            }
+        }
+    }
+
+    /// Links a new node in front of everything not ordered before it, so a
+    /// repeated `(key, seq)` shadows the earlier insert.
+    fn insert(&self, key: &[u8], seq: SeqNo, kind: u8, value: &[u8]) {
+        let height = random_height();
+        let node = NodeRef::alloc(&self.arena, height, key, seq, kind, value);
+        let list_height = self.height.fetch_max(height, Ordering::AcqRel).max(height);
+
+        let mut prev = [self.head; MAX_HEIGHT];
+        let mut next = [None; MAX_HEIGHT];
+        let mut x = self.head;
+        for level in (0..list_height).rev() {
+            let (p, n) = self.find_splice(x, key, seq, level);
+            prev[level] = p;
+            next[level] = n;
+            x = p;
+        }
+        for level in 0..height {
+            loop {
+                node.set_next(level, next[level]);
+                if prev[level].cas_next(level, next[level], node) {
+                    break;
                 }
+                // Another insert landed between; search again from where
+                // this level's splice started.
+                let (p, n) = self.find_splice(prev[level], key, seq, level);
+                prev[level] = p;
+                next[level] = n;
+            }
+        }
+        self.len.fetch_add(1, Ordering::AcqRel);
+        self.max_seq.fetch_max(seq, Ordering::AcqRel);
+    }
+
+    /// Neighbours `(key, seq)` would sit between on `level`, searching
+    /// forward from `start`.
+    fn find_splice(
+        &self,
+        start: NodeRef,
+        key: &[u8],
+        seq: SeqNo,
+        level: usize,
+    ) -> (NodeRef, Option<NodeRef>) {
+        let mut x = start;
+        loop {
+            match x.next(level) {
+                Some(next) if next.cmp_to(key, seq) == CmpOrdering::Less => x = next,
+                next => return (x, next),
             }
         }
     }
 }
+
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        self.head.free_head();
+    }
+}
+
+/// Versions read from a memtable, borrowed (`&MemTable`) or shared
+/// (`Arc<MemTable>`). The iterator follows the list without locking, so
+/// writes interleave with a long scan; a write landing ahead of the cursor
+/// may or may not be seen.
+pub struct MemTableIter<M> {
+    /// Keeps the nodes the cursor points at alive.
+    _mem: M,
+    node: Option<NodeRef>,
+}
+
+// SAFETY: the cursor points into the arena of the memtable `mem` keeps
+// alive, whose nodes are immutable once linked.
+unsafe impl<M: Send> Send for MemTableIter<M> {}
+
+impl<M: Deref<Target = MemTable>> MemTableIter<M> {
+    pub fn new(mem: M, start: &[u8]) -> Self {
+        let node = mem.seek(start, MAX_SEQ);
+        Self { _mem: mem, node }
+    }
+}
+
+impl<M: Deref<Target = MemTable>> Iterator for MemTableIter<M> {
+    type Item = (Vec<u8>, SeqNo, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.node?;
+        self.node = node.next(0);
+        Some((node.key().to_vec(), node.header().seq, node.entry()))
+    }
+}
+
+/// Picks a tower height with each level a quarter as likely as the last.
+fn random_height() -> usize {
+    let mut bits = rand::random::<u32>();
+    let mut height = 1;
+    while height < MAX_HEIGHT && bits & 3 == 0 {
+        height += 1;
+        bits >>= 2;
+    }
+    height
+}
+
+/// Fixed part of a skiplist node. In the arena it is followed by `height`
+/// next pointers, then the key, then the value.
+#[repr(C)]
+struct Header {
+    seq: SeqNo,
+    key_len: u32,
+    value_len: u32,
+    height: u16,
+    kind: u8,
+}
+
+const HEADER: usize = std::mem::size_of::<Header>();
+const LINK: usize = std::mem::size_of::<AtomicPtr<Header>>();
+
+/// Pointer to a node in an arena. Only valid while the memtable owning the
+/// arena is alive; every use is confined to `MemTable` and its iterator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct NodeRef(NonNull<Header>);
+
+impl NodeRef {
+    fn node_size(height: usize, key_len: usize, value_len: usize) -> usize {
+        HEADER + height * LINK + key_len + value_len
+    }
+
+    fn alloc(arena: &Arena, height: usize, key: &[u8], seq: SeqNo, kind: u8, value: &[u8]) -> Self {
+        let key_len = u32::try_from(key.len()).expect("memtable key over 4 GiB");
+        let value_len = u32::try_from(value.len()).expect("memtable value over 4 GiB");
+        let size = Self::node_size(height, key.len(), value.len());
+        let ptr = arena.alloc(size).cast::<Header>();
+        // SAFETY: `ptr` is a fresh, aligned, zeroed allocation of `size`
+        // bytes; zeroed next pointers are null.
+        unsafe {
+            ptr.as_ptr().write(Header {
+                seq,
+                key_len,
+                value_len,
+                height: height as u16,
+                kind,
+            });
+            let data = ptr.as_ptr().cast::<u8>().add(HEADER + height * LINK);
+            ptr::copy_nonoverlapping(key.as_ptr(), data, key.len());
+            ptr::copy_nonoverlapping(value.as_ptr(), data.add(key.len()), value.len());
+        }
+        Self(ptr)
+    }
+
+    /// The list head: a full-height node with an empty key, allocated on its
+    /// own so it does not count towards the arena's usage.
+    fn alloc_head() -> Self {
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(Self::head_layout()) };
+        let ptr = NonNull::new(ptr)
+            .unwrap_or_else(|| alloc::handle_alloc_error(Self::head_layout()))
+            .cast::<Header>();
+        // SAFETY: as in `alloc`.
+        unsafe {
+            ptr.as_ptr().write(Header {
+                seq: MAX_SEQ,
+                key_len: 0,
+                value_len: 0,
+                height: MAX_HEIGHT as u16,
+                kind: KIND_DELETE,
+            })
+        };
+        Self(ptr)
+    }
+
+    fn head_layout() -> Layout {
+        Layout::from_size_align(Self::node_size(MAX_HEIGHT, 0, 0), ARENA_ALIGN).unwrap()
+    }
+
+    /// Frees a node made by `alloc_head`.
+    fn free_head(self) {
+        // SAFETY: allocated in `alloc_head` with the same layout.
+        unsafe { alloc::dealloc(self.0.as_ptr().cast(), Self::head_layout()) }
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the header is fully written before the node is reachable.
+        unsafe { self.0.as_ref() }
+    }
+
+    fn link(&self, level: usize) -> &AtomicPtr<Header> {
+        debug_assert!(level < self.header().height as usize);
+        // SAFETY: the tower has `height` pointers right after the header.
+        unsafe {
+            &*self
+                .0
+                .as_ptr()
+                .cast::<u8>()
+                .add(HEADER + level * LINK)
+                .cast::<AtomicPtr<Header>>()
+        }
+    }
+
+    fn next(&self, level: usize) -> Option<NodeRef> {
+        NonNull::new(self.link(level).load(Ordering::Acquire)).map(NodeRef)
+    }
+
+    fn set_next(&self, level: usize, next: Option<NodeRef>) {
+        self.link(level).store(as_ptr(next), Ordering::Relaxed);
+    }
+
+    /// Points `level` at `node` if it still points at `expected`.
+    fn cas_next(&self, level: usize, expected: Option<NodeRef>, node: NodeRef) -> bool {
+        self.link(level)
+            .compare_exchange(
+                as_ptr(expected),
+                node.0.as_ptr(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+
+    fn data(&self, offset: usize, len: usize) -> &[u8] {
+        let h = self.header();
+        let start = HEADER + h.height as usize * LINK + offset;
+        // SAFETY: key and value bytes follow the tower and were written
+        // before the node was published.
+        unsafe { std::slice::from_raw_parts(self.0.as_ptr().cast::<u8>().add(start), len) }
+    }
+
+    fn key(&self) -> &[u8] {
+        self.data(0, self.header().key_len as usize)
+    }
+
+    fn entry(&self) -> Entry {
+        let h = self.header();
+        match h.kind {
+            KIND_PUT => Entry::Put(self.data(h.key_len as usize, h.value_len as usize).to_vec()),
+            _ => Entry::Delete,
+        }
+    }
+
+    /// Orders this node against `(key, seq)`: by key, then newest first.
+    /// Equal versions compare greater, so new inserts go in front of them.
+    fn cmp_to(&self, key: &[u8], seq: SeqNo) -> CmpOrdering {
+        match self.key().cmp(key) {
+            CmpOrdering::Equal if self.header().seq > seq => CmpOrdering::Less,
+            CmpOrdering::Equal => CmpOrdering::Greater,
+            ord => ord,
+        }
+    }
+}
+
+fn as_ptr(node: Option<NodeRef>) -> *mut Header {
+    node.map_or(ptr::null_mut(), |n| n.0.as_ptr())
+}
+
+const ARENA_ALIGN: usize = std::mem::align_of::<Header>();
+
+/// Bump allocator handing out zeroed, aligned space from large chunks that
+/// are only freed when the arena is dropped.
+struct Arena {
+    chunks: Mutex<Chunks>,
+    chunk_bytes: usize,
+    used: AtomicUsize,
+}
+
+struct Chunks {
+    /// Every chunk with its layout, current one last.
+    all: Vec<(NonNull<u8>, Layout)>,
+    /// Offset of the free space in the current chunk.
+    offset: usize,
+}
+
+impl Arena {
+    fn new(chunk_bytes: usize) -> Self {
+        Self {
+            chunks: Mutex::new(Chunks {
+                all: Vec::new(),
+                offset: 0,
+            }),
+            chunk_bytes,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    fn alloc(&self, size: usize) -> NonNull<u8> {
+        let size = size.next_multiple_of(ARENA_ALIGN);
+        let mut chunks = self.chunks.lock().unwrap();
+        let room = chunks
+            .all
+            .last()
+            .map_or(0, |(_, l)| l.size() - chunks.offset);
+        if size > room {
+            // The rest of the current chunk is abandoned, so it counts as used.
+            self.used.fetch_add(room, Ordering::AcqRel);
+            let layout = Layout::from_size_align(self.chunk_bytes.max(size), ARENA_ALIGN).unwrap();
+            // SAFETY: the layout has a non-zero size.
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+            chunks.all.push((ptr, layout));
+            chunks.offset = 0;
+        }
+        let (base, _) = chunks.all[chunks.all.len() - 1];
+        // SAFETY: `offset + size` is within the current chunk.
+        let ptr = unsafe { NonNull::new_unchecked(base.as_ptr().add(chunks.offset)) };
+        chunks.offset += size;
+        self.used.fetch_add(size, Ordering::AcqRel);
+        ptr
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let chunks = self.chunks.get_mut().unwrap_or_else(|e| e.into_inner());
+        for (ptr, layout) in chunks.all.drain(..) {
+            // SAFETY: allocated in `alloc` with this layout.
+            unsafe { alloc::dealloc(ptr.as_ptr(), layout) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn concurrent_inserts_keep_versions_ordered() {
+        let mem = Arc::new(MemTable::new(usize::MAX));
+        let writers: Vec<_> = (0..8u64)
+            .map(|t| {
+                let mem = mem.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500u64 {
+                        let key = format!("k{:03}", i % 100);
+                        let seq = t * 1000 + i + 1;
+                        if i % 7 == 0 {
+                            mem.delete(key.as_bytes(), seq);
+                        } else {
+                            mem.put(key.as_bytes(), seq, &seq.to_be_bytes());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(mem.len(), 4000);
+        assert_eq!(mem.max_seq(), 7500);
+        let all: Vec<_> = mem.iter().collect();
+        assert_eq!(all.len(), 4000);
+        assert!(all
+            .windows(2)
+            .all(|w| (&w[0].0, std::cmp::Reverse(w[0].1)) < (&w[1].0, std::cmp::Reverse(w[1].1))));
+        assert_eq!(mem.smallest_key(), Some(b"k000".to_vec()));
+        assert_eq!(mem.largest_key(), Some(b"k099".to_vec()));
+        // k001 was last written by thread 7 at i = 401.
+        assert!(matches!(mem.get(b"k001"), Some(Entry::Put(v)) if v == 7402u64.to_be_bytes()));
+        assert!(matches!(mem.get_at(b"k001", 7401), Some(Entry::Delete)));
+        assert!(mem.get(b"k100").is_none());
+        assert_eq!(mem.range_from(b"k099").count(), 40);
+    }
+
+    #[test]
+    fn bytes_used_counts_nodes_and_abandoned_chunk_tails() {
+        let mem = MemTable::new(4096);
+        assert_eq!(mem.bytes_used(), 0);
+        mem.put(b"key", 1, b"value");
+        let first = mem.bytes_used();
+        assert!(first >= HEADER + LINK + 8);
+        assert_eq!(first % ARENA_ALIGN, 0);
+        mem.delete(b"key", 2);
+        assert!(mem.bytes_used() >= first + HEADER + LINK + 3);
+
+        // A value too big for the rest of the chunk starts a new one and
+        // writes off what was left of the old one.
+        mem.put(b"big", 3, &[7; 4000]);
+        assert!(mem.bytes_used() >= 4096 + 4000);
+        assert!(mem.over_threshold());
+        assert!(matches!(mem.get(b"big"), Some(Entry::Put(v)) if v.len() == 4000));
+        assert!(matches!(mem.get(b"key"), Some(Entry::Delete)));
+    }
+}
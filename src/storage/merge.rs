@@ -0,0 +1,207 @@
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::sstable::range_del::{self, RangeTombstone};
+use std::cmp::Reverse;
+use std::io::Result;
+
+/// A sorted stream of versioned entries from one memtable or SSTable, in
+/// (key ascending, seq descending) order.
+pub type EntryIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, SeqNo, Entry)>> + 'a>;
+
+/// Merges sorted sources into a single stream ordered by key and then newest
+/// sequence number first.
+///
+/// Every version is yielded, tombstones included, so callers can pick the
+/// version visible at a snapshot or decide which ones to keep. Sources are
+/// given newest first and win ties on (key, seq).
+pub struct MergingIter<'a> {
+    sources: Vec<EntryIter<'a>>,
+    heads: Vec<Option<(Vec<u8>, SeqNo, Entry)>>,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> MergingIter<'a> {
+    pub fn new(sources: Vec<EntryIter<'a>>) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        Self {
+            sources,
+            heads,
+            started: false,
+            done: false,
+        }
+    }
+
+    fn refill(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, SeqNo, Entry)>> {
+        if !self.started {
+            for i in 0..self.sources.len() {
+                self.refill(i)?;
+            }
+            self.started = true;
+        }
+        // `min_by_key` keeps the first minimum, so earlier sources win ties.
+        let min = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| h.as_ref().map(|(k, s, _)| (i, (k, Reverse(*s)))))
+            .min_by_key(|(_, order)| *order)
+            .map(|(i, _)| i);
+        let Some(min) = min else {
+            return Ok(None);
+        };
+        let head = self.heads[min].take();
+        self.refill(min)?;
+        Ok(head)
+    }
+}
+
+impl Iterator for MergingIter<'_> {
+    type Item = Result<(Vec<u8>, SeqNo, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(e)) => Some(Ok(e)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Live key/value pairs in `[start, end)` as of snapshot `seq`: the newest
+/// version per key with a sequence number `<= seq`, with deleted keys hidden.
+pub struct ScanIter<'a> {
+    inner: MergingIter<'a>,
+    end: Option<Vec<u8>>,
+    seq: SeqNo,
+    last_key: Option<Vec<u8>>,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl<'a> ScanIter<'a> {
+    pub fn new(inner: MergingIter<'a>, end: Option<Vec<u8>>, seq: SeqNo) -> Self {
+        Self {
+            inner,
+            end,
+            seq,
+            last_key: None,
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    /// Also hides versions covered by any of `tombstones`, which should
+    /// come from the same sources as the merged entries.
+    pub fn with_range_tombstones(mut self, tombstones: Vec<RangeTombstone>) -> Self {
+        self.range_tombstones = tombstones;
+        self
+    }
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, seq, entry) = match self.inner.next()? {
+                Ok(e) => e,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(end) = &self.end {
+                if key.as_slice() >= end.as_slice() {
+                    self.inner.done = true;
+                    return None;
+                }
+            }
+            if seq > self.seq || self.last_key.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_key = Some(key.clone());
+            let covered = self
+                .range_tombstones
+                .iter()
+                .any(|t| t.covers(&key, seq, self.seq));
+            if covered {
+                continue;
+            }
+            if let Entry::Put(value) = entry {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+/// Decides which versions survive a flush or compaction.
+///
+/// Fed the merged stream in order, it keeps the newest version of each key
+/// plus any older version that a snapshot at or above `smallest_snapshot`
+/// could still read. With `drop_tombstones` set, tombstones no snapshot can
+/// see past are dropped too; only safe when no older table holds the key.
+/// Versions hidden by a range tombstone every snapshot sees are dropped.
+pub struct VersionGc {
+    smallest_snapshot: SeqNo,
+    drop_tombstones: bool,
+    last_key: Option<Vec<u8>>,
+    last_seq_for_key: SeqNo,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl VersionGc {
+    pub fn new(smallest_snapshot: SeqNo, drop_tombstones: bool) -> Self {
+        Self {
+            smallest_snapshot,
+            drop_tombstones,
+            last_key: None,
+            last_seq_for_key: SeqNo::MAX,
+            range_tombstones: Vec::new(),
+        }
+    }
+
+    /// Range tombstones from the sources being merged.
+    pub fn with_range_tombstones(mut self, tombstones: Vec<RangeTombstone>) -> Self {
+        self.range_tombstones = tombstones;
+        self
+    }
+
+    pub fn keep(&mut self, key: &[u8], seq: SeqNo, entry: &Entry) -> bool {
+        if self.last_key.as_deref() != Some(key) {
+            self.last_key = Some(key.to_vec());
+            self.last_seq_for_key = SeqNo::MAX;
+        }
+        // A newer version, or range tombstone, already visible to every
+        // snapshot shadows this one.
+        let shadowed = self.last_seq_for_key <= self.smallest_snapshot
+            || range_del::newest_covering(&self.range_tombstones, key, self.smallest_snapshot)
+                .is_some_and(|t| t > seq);
+        let dropped_tombstone =
+            self.drop_tombstones && matches!(entry, Entry::Delete) && seq <= self.smallest_snapshot;
+        let keep = !shadowed && !dropped_tombstone;
+        self.last_seq_for_key = seq;
+        keep
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, or `None`
+/// when no such key exists (the prefix is empty or all `0xff`).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
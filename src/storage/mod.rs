@@ -1,3 +1,9 @@
+pub mod compaction;
+pub mod lock;
 pub mod manifest;
 pub mod memtable;
+pub mod merge;
+pub mod repair;
+pub mod snapshot;
 pub mod sstable;
+pub mod wal;
@@ -0,0 +1,516 @@
+use crate::storage::lock::DirLock;
+use crate::storage::manifest::{
+    fsync_dir, manifest_name, manifest_number, read_manifest_state, remove_stale_manifests,
+    write_current_atomic, Manifest, ManifestEdit,
+};
+use crate::storage::memtable::{Entry, SeqNo};
+use crate::storage::sstable::range_del;
+use crate::storage::sstable::reader::SsTableReader;
+use crate::storage::sstable::{table_id_from_name, table_path, TableId, TableMeta};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Directory under the data dir that repair moves unusable files into.
+pub const QUARANTINE_DIR: &str = "lost";
+
+/// What a full read of a table found. `meta` is at level 0; the level
+/// lives in the manifest, not in the table.
+#[derive(Clone, Debug)]
+pub struct TableSummary {
+    pub meta: TableMeta,
+    pub min_seq: SeqNo,
+    pub max_seq: SeqNo,
+    pub db_id: Option<u128>,
+    pub num_blocks: usize,
+}
+
+/// Result of checking one `sst/*.sst` file.
+#[derive(Debug)]
+pub struct TableCheck {
+    pub path: PathBuf,
+    pub result: Result<TableSummary>,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub tables: Vec<TableCheck>,
+    /// Why the manifest CURRENT names could not be read, if it could not.
+    pub manifest_error: Option<String>,
+    /// Tables the manifest references that have no file in `sst/`.
+    pub missing: Vec<TableId>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.manifest_error.is_none()
+            && self.missing.is_empty()
+            && self.tables.iter().all(|t| t.result.is_ok())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Name of the manifest repair wrote and pointed CURRENT at.
+    pub manifest: String,
+    /// Tables recorded in the new manifest.
+    pub tables: Vec<TableMeta>,
+    /// Files moved into `lost/`, by their original path.
+    pub quarantined: Vec<PathBuf>,
+    /// Tables the old manifest referenced that were missing or corrupt.
+    pub dropped: Vec<TableId>,
+    /// True when the old manifest was unreadable and levels were
+    /// reassigned from the tables' sequence numbers.
+    pub rebuilt_levels: bool,
+}
+
+/// Reads every entry of the table at `path`, checking what opening a table
+/// checks (footer magic and version, and the index crc via `Index::decode`)
+/// plus every block crc, that keys ascend with versions of one key in
+/// descending seq order, and that the index and properties agree with the
+/// entries found.
+pub fn check_table(path: &Path) -> Result<TableSummary> {
+    let bad = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let reader = SsTableReader::open(path)?;
+    let name_id = path
+        .file_name()
+        .and_then(|n| table_id_from_name(&n.to_string_lossy()));
+    let id = match (reader.properties().map(|p| p.table_id), name_id) {
+        (Some(stored), Some(named)) if stored != 0 && stored != named => {
+            return Err(bad(format!("holds table {stored}, not table {named}")));
+        }
+        (_, Some(named)) => named,
+        (Some(stored), None) => stored,
+        (None, None) => 0,
+    };
+
+    let mut meta = TableMeta {
+        id,
+        file_len: fs::metadata(path)?.len(),
+        ..TableMeta::default()
+    };
+    let (mut min_seq, mut max_seq) = (SeqNo::MAX, 0);
+    let mut prev: Option<(Vec<u8>, SeqNo)> = None;
+    let handles = reader.block_handles();
+    for (i, &handle) in handles.iter().enumerate() {
+        let block = reader
+            .block(handle)
+            .map_err(|e| bad(format!("block {i} at offset {}: {e}", handle.offset)))?;
+        let mut entries = 0;
+        for (key, seq, entry) in block.iter() {
+            if let Some((prev_key, prev_seq)) = &prev {
+                let ordered = match key.as_slice().cmp(prev_key) {
+                    std::cmp::Ordering::Greater => true,
+                    std::cmp::Ordering::Equal => seq < *prev_seq,
+                    std::cmp::Ordering::Less => false,
+                };
+                if !ordered {
+                    return Err(bad(format!(
+                        "block {i}: key {} seq {seq} out of order after key {} seq {prev_seq}",
+                        hex::encode(&key),
+                        hex::encode(prev_key)
+                    )));
+                }
+            }
+            if meta.num_entries == 0 {
+                meta.smallest = key.clone();
+            }
+            meta.num_entries += 1;
+            if matches!(entry, Entry::Delete) {
+                meta.num_deletions += 1;
+            }
+            min_seq = min_seq.min(seq);
+            max_seq = max_seq.max(seq);
+            entries += 1;
+            prev = Some((key, seq));
+        }
+        if entries == 0 {
+            return Err(bad(format!(
+                "block {i} at offset {} is empty",
+                handle.offset
+            )));
+        }
+    }
+    if let Some((key, _)) = prev {
+        meta.largest = key;
+    }
+    if reader.last_key().is_some_and(|k| k != meta.largest) {
+        return Err(bad("index does not end at the last key".to_string()));
+    }
+    let tombstones = reader.range_tombstones();
+    if let Some(props) = reader.properties() {
+        let found = (meta.num_entries, meta.num_deletions, handles.len() as u64);
+        let recorded = (
+            props.num_entries,
+            props.num_deletions,
+            props.num_data_blocks,
+        );
+        if found != recorded {
+            return Err(bad(format!(
+                "properties record {recorded:?} entries, deletions and blocks, found {found:?}"
+            )));
+        }
+        if props.num_range_deletions != tombstones.len() as u64 {
+            return Err(bad(format!(
+                "properties record {} range tombstones, found {}",
+                props.num_range_deletions,
+                tombstones.len()
+            )));
+        }
+    }
+    if let Some(t) = tombstones.iter().find(|t| t.start >= t.end) {
+        return Err(bad(format!(
+            "empty range tombstone at {} seq {}",
+            hex::encode(&t.start),
+            t.seq
+        )));
+    }
+    let points = (meta.num_entries > 0).then(|| (meta.smallest.clone(), meta.largest.clone()));
+    (meta.smallest, meta.largest) = range_del::key_bounds(points, tombstones);
+    for t in tombstones {
+        min_seq = min_seq.min(t.seq);
+        max_seq = max_seq.max(t.seq);
+    }
+    Ok(TableSummary {
+        meta,
+        min_seq: if max_seq == 0 { 0 } else { min_seq },
+        max_seq,
+        db_id: reader.db_id(),
+        num_blocks: handles.len(),
+    })
+}
+
+/// Checks every table in `data_dir/sst` and the manifest's view of them,
+/// without modifying anything.
+pub fn verify(data_dir: &Path) -> Result<VerifyReport> {
+    let files = table_files(data_dir)?;
+    let present: HashSet<TableId> = files.iter().map(|(id, _)| *id).collect();
+    let mut report = VerifyReport {
+        tables: files
+            .into_iter()
+            .map(|(_, path)| {
+                let result = check_table(&path);
+                TableCheck { path, result }
+            })
+            .collect(),
+        ..VerifyReport::default()
+    };
+    match read_manifest_state(data_dir) {
+        Ok(state) => {
+            report.missing = state
+                .tables
+                .iter()
+                .map(|t| t.id)
+                .filter(|id| !present.contains(id))
+                .collect();
+        }
+        Err(e) => report.manifest_error = Some(e.to_string()),
+    }
+    Ok(report)
+}
+
+/// Rewrites the manifest from the tables that pass `check_table`, moving
+/// corrupt tables into `lost/`.
+///
+/// If the current manifest is readable it is trusted for which tables are
+/// live and at what level; tables it does not reference are left for the
+/// engine to collect, since resurrecting a compaction input could bring back
+/// deleted keys. Otherwise every readable table is kept, and levels are
+/// rebuilt so that wherever two tables overlap, the one holding newer
+/// sequence numbers is searched first. Takes the directory lock, so the
+/// engine must not be running.
+pub fn repair(data_dir: &Path) -> Result<RepairReport> {
+    let _lock = DirLock::acquire(data_dir)?;
+    let lost_dir = data_dir.join(QUARANTINE_DIR);
+    let mut report = RepairReport::default();
+
+    let mut readable = Vec::new();
+    for (_, path) in table_files(data_dir)? {
+        match check_table(&path) {
+            Ok(summary) => readable.push(summary),
+            Err(_) => report.quarantined.push(quarantine(&path, &lost_dir)?),
+        }
+    }
+
+    let old = read_manifest_state(data_dir).ok();
+    let db_id = match &old {
+        Some(state) => state.db_id,
+        None => most_common_db_id(&readable),
+    };
+    let foreign: Vec<TableId> = readable
+        .iter()
+        .filter(|t| matches!((t.db_id, db_id), (Some(a), Some(b)) if a != b))
+        .map(|t| t.meta.id)
+        .collect();
+    let sst_dir = data_dir.join("sst");
+    for &id in &foreign {
+        let path = table_path(&sst_dir, id);
+        report.quarantined.push(quarantine(&path, &lost_dir)?);
+    }
+    readable.retain(|t| !foreign.contains(&t.meta.id));
+
+    let mut last_seq = readable.iter().map(|t| t.max_seq).max().unwrap_or(0);
+    match &old {
+        Some(state) => {
+            last_seq = last_seq.max(state.last_seq);
+            let by_id: HashMap<TableId, &TableSummary> =
+                readable.iter().map(|t| (t.meta.id, t)).collect();
+            for live in &state.tables {
+                match by_id.get(&live.id) {
+                    Some(summary) => report.tables.push(TableMeta {
+                        level: live.level,
+                        ..summary.meta.clone()
+                    }),
+                    None => report.dropped.push(live.id),
+                }
+            }
+        }
+        None => {
+            report.tables = assign_levels(&readable);
+            report.rebuilt_levels = true;
+        }
+    }
+
+    let number = fs::read_dir(data_dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| manifest_number(&e.file_name().to_string_lossy()))
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let name = manifest_name(number);
+    let path = data_dir.join(&name);
+    let mut manifest = Manifest::new(path.clone())?;
+    manifest.record(ManifestEdit {
+        added: report.tables.clone(),
+        removed: Vec::new(),
+        last_seq: Some(last_seq),
+        db_id,
+    })?;
+    fsync_dir(&path)?;
+    drop(manifest);
+    // CURRENT must name the new manifest before any old one leaves the
+    // directory, or a crash in between leaves nothing to open.
+    write_current_atomic(data_dir, &name)?;
+    if old.is_none() {
+        // Keep the unreadable manifests for inspection rather than
+        // letting `remove_stale_manifests` delete them.
+        for entry in fs::read_dir(data_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            if manifest_number(&file_name.to_string_lossy()).is_some() && file_name != *name {
+                report
+                    .quarantined
+                    .push(quarantine(&entry.path(), &lost_dir)?);
+            }
+        }
+    }
+    remove_stale_manifests(data_dir, &name)?;
+    report.manifest = name;
+    Ok(report)
+}
+
+/// Finished table files in `data_dir/sst`, in id order.
+fn table_files(data_dir: &Path) -> Result<Vec<(TableId, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(data_dir.join("sst"))? {
+        let entry = entry?;
+        if let Some(id) = table_id_from_name(&entry.file_name().to_string_lossy()) {
+            files.push((id, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Moves `path` into `lost_dir`, returning the path it was moved from. A
+/// file quarantined earlier under the same name is kept: the newcomer gets
+/// the first free `.1`, `.2`, ... suffix.
+fn quarantine(path: &Path, lost_dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(lost_dir)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut target = lost_dir.join(&*file_name);
+    let mut n = 0;
+    while target.try_exists()? {
+        n += 1;
+        target = lost_dir.join(format!("{file_name}.{n}"));
+    }
+    fs::rename(path, &target)?;
+    fsync_dir(path)?;
+    fsync_dir(&target)?;
+    Ok(path.to_path_buf())
+}
+
+fn most_common_db_id(tables: &[TableSummary]) -> Option<u128> {
+    let mut counts: HashMap<u128, usize> = HashMap::new();
+    for id in tables.iter().filter_map(|t| t.db_id) {
+        *counts.entry(id).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(id, count)| (count, id))
+        .map(|(id, _)| id)
+}
+
+/// Places tables, newest first, one level below the deepest newer table they
+/// overlap. Lookups search levels top down, so the newest version of a key
+/// is always found first, and tables sharing a level never overlap.
+fn assign_levels(tables: &[TableSummary]) -> Vec<TableMeta> {
+    let mut order: Vec<&TableSummary> = tables.iter().collect();
+    order.sort_by(|a, b| b.max_seq.cmp(&a.max_seq).then(b.meta.id.cmp(&a.meta.id)));
+    let mut placed: Vec<TableMeta> = Vec::with_capacity(order.len());
+    for table in order {
+        let level = placed
+            .iter()
+            .filter(|p| p.overlaps(&table.meta.smallest, &table.meta.largest))
+            .map(|p| p.level + 1)
+            .max()
+            .unwrap_or(0);
+        placed.push(TableMeta {
+            level,
+            ..table.meta.clone()
+        });
+    }
+    placed.sort_by_key(|t| t.id);
+    placed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::kv::{EngineOptions, LsmEngine};
+    use crate::storage::manifest::current_path;
+    use crate::storage::sstable::builder::SsTableBuilder;
+    use crate::storage::sstable::TableOptions;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zynk-repair-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn corrupt_first_block(path: &Path) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[3] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_corrupt_and_missing_tables() {
+        let dir = temp_dir("verify");
+        let sst = dir.join("sst");
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            for (k, v) in [(b"a", b"1"), (b"b", b"2"), (b"c", b"3")] {
+                eng.put(k, v).unwrap();
+                eng.flush().unwrap();
+            }
+        }
+        let report = verify(&dir).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.tables.len(), 3);
+        let summary = report.tables[0].result.as_ref().unwrap();
+        assert_eq!(summary.meta.smallest, b"a");
+        assert_eq!((summary.meta.num_entries, summary.num_blocks), (1, 1));
+
+        corrupt_first_block(&table_path(&sst, 2));
+        fs::remove_file(table_path(&sst, 3)).unwrap();
+        let report = verify(&dir).unwrap();
+        assert!(!report.is_ok());
+        let err = report.tables[1].result.as_ref().unwrap_err().to_string();
+        assert!(err.contains("block 0 at offset 0: block crc"), "{err}");
+        assert_eq!(report.missing, vec![3]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repair_quarantines_corrupt_tables_and_rebuilds_a_lost_manifest() {
+        let dir = temp_dir("rebuild");
+        let sst = dir.join("sst");
+        let db_id;
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            db_id = eng.db_id();
+            eng.put(b"k", b"old").unwrap();
+            eng.put(b"x", b"1").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"k", b"new").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"y", b"2").unwrap();
+            eng.flush().unwrap();
+        }
+        corrupt_first_block(&table_path(&sst, 3));
+        let manifest = fs::read_to_string(current_path(&dir)).unwrap();
+        fs::remove_file(current_path(&dir)).unwrap();
+
+        let report = repair(&dir).unwrap();
+        assert!(report.rebuilt_levels);
+        assert!(report.quarantined.contains(&table_path(&sst, 3)));
+        assert!(dir.join(QUARANTINE_DIR).join("000003.sst").exists());
+        assert!(dir.join(QUARANTINE_DIR).join(manifest.trim()).exists());
+        let levels: Vec<_> = report.tables.iter().map(|t| (t.id, t.level)).collect();
+        assert_eq!(levels, vec![(1, 1), (2, 0)]);
+        assert!(verify(&dir).unwrap().is_ok());
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.db_id(), db_id);
+        assert_eq!(eng.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(eng.get(b"x").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"y").unwrap(), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn quarantine_keeps_earlier_files_of_the_same_name() {
+        let dir = temp_dir("same-name");
+        fs::create_dir_all(&dir).unwrap();
+        let lost = dir.join(QUARANTINE_DIR);
+        let path = dir.join("000001.sst");
+        for body in ["first", "second", "third"] {
+            fs::write(&path, body).unwrap();
+            assert_eq!(quarantine(&path, &lost).unwrap(), path);
+            assert!(!path.exists());
+        }
+        let read = |name: &str| fs::read_to_string(lost.join(name)).unwrap();
+        assert_eq!(read("000001.sst"), "first");
+        assert_eq!(read("000001.sst.1"), "second");
+        assert_eq!(read("000001.sst.2"), "third");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repair_keeps_manifest_levels_and_ignores_unreferenced_tables() {
+        let dir = temp_dir("keep");
+        let sst = dir.join("sst");
+        {
+            let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+            eng.put(b"a", b"1").unwrap();
+            eng.flush().unwrap();
+            eng.put(b"b", b"2").unwrap();
+            eng.flush().unwrap();
+            // A compaction input whose removal from the manifest was
+            // recorded but whose file was never deleted.
+            let opts = TableOptions {
+                db_id: eng.db_id(),
+                ..TableOptions::default()
+            };
+            let mut stale = SsTableBuilder::new(&table_path(&sst, 9), 9, &opts).unwrap();
+            stale.add_put(b"a", 100, b"stale").unwrap();
+            stale.finish().unwrap();
+        }
+        corrupt_first_block(&table_path(&sst, 2));
+
+        let report = repair(&dir).unwrap();
+        assert!(!report.rebuilt_levels);
+        assert_eq!(report.dropped, vec![2]);
+        assert_eq!(report.quarantined, vec![table_path(&sst, 2)]);
+        let ids: Vec<_> = report.tables.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![1]);
+
+        let eng = LsmEngine::open(&dir, EngineOptions::default()).unwrap();
+        assert_eq!(eng.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"b").unwrap(), None);
+        assert!(!table_path(&sst, 9).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::storage::memtable::SeqNo;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Reference counts of the sequence numbers pinned by live snapshots.
+#[derive(Clone, Default)]
+pub struct SnapshotList {
+    pinned: Arc<Mutex<BTreeMap<SeqNo, usize>>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins the sequence number `current` returns until the returned handle
+    /// is dropped. `current` runs under the same lock as in `oldest_or`, so
+    /// the two never interleave.
+    pub fn acquire(&self, current: impl FnOnce() -> SeqNo) -> Snapshot {
+        let mut pinned = self.pinned.lock().unwrap();
+        let seq = current();
+        *pinned.entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            pinned: self.pinned.clone(),
+        }
+    }
+
+    /// Oldest sequence number still pinned by a snapshot, or what `current`
+    /// returns when there is none. A snapshot acquired afterwards pins a
+    /// number no smaller than the result, as long as `current` never goes
+    /// backwards.
+    pub fn oldest_or(&self, current: impl FnOnce() -> SeqNo) -> SeqNo {
+        let pinned = self.pinned.lock().unwrap();
+        match pinned.keys().next() {
+            Some(&seq) => seq,
+            None => current(),
+        }
+    }
+}
+
+/// A point-in-time view of the engine: reads through it only see writes with
+/// a sequence number `<= seq()`. Compaction keeps the versions it needs for
+/// as long as the handle is alive.
+pub struct Snapshot {
+    seq: SeqNo,
+    pinned: Arc<Mutex<BTreeMap<SeqNo, usize>>>,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> SeqNo {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut pinned = self.pinned.lock().unwrap();
+        if let Some(count) = pinned.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(&self.seq);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sampling_waits_for_a_snapshot_being_acquired() {
+        let list = SnapshotList::new();
+        assert_eq!(list.oldest_or(|| 7), 7);
+        std::thread::scope(|s| {
+            let mut sampler = None;
+            // Writes move on to 150 while the snapshot at 100 is being
+            // registered; the sample must not skip past it.
+            let snap = list.acquire(|| {
+                sampler = Some(s.spawn(|| list.oldest_or(|| 150)));
+                std::thread::sleep(Duration::from_millis(20));
+                100
+            });
+            assert_eq!(sampler.unwrap().join().unwrap(), 100);
+            assert_eq!(snap.seq(), 100);
+            drop(snap);
+            assert_eq!(list.oldest_or(|| 150), 150);
+        });
+    }
+}
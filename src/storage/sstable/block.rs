@@ -1,50 +1,82 @@
-use crc32fast::Hasher;
+use crate::storage::memtable::{Entry, SeqNo};
+use std::sync::Arc;
 
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+/// Builds a prefix-compressed data block (table version 4 and later).
+///
+/// Entry layout: `shared | unshared | vlen | seq` as varints, then `op u8`,
+/// the unshared key suffix and the value. `shared` is the length of the
+/// prefix shared with the previous key; it is 0 every `restart_interval`
+/// entries, at a restart point. The block ends with the restart offsets as
+/// `u32`s and their count as a `u32`; compression and the crc are applied
+/// when the builder writes it out.
 pub struct DataBlock {
     target_bytes: usize,
+    restart_interval: usize,
     payload: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: Vec<u8>,
+    since_restart: usize,
     entries: usize,
 }
 
 impl DataBlock {
-    pub fn new(target_bytes: usize) -> Self {
+    pub fn new(target_bytes: usize, restart_interval: usize) -> Self {
         Self {
             target_bytes,
+            restart_interval: restart_interval.max(1),
             payload: Vec::with_capacity(target_bytes),
+            restarts: Vec::new(),
+            last_key: Vec::new(),
+            since_restart: 0,
             entries: 0,
         }
     }
 
-    pub fn add_put(&mut self, key: &[u8], value: &[u8]) {
-        self.payload.push(0);
-        self.payload
-            .extend_from_slice(&(key.len() as u32).to_le_bytes());
-        self.payload
-            .extend_from_slice(&(value.len() as u32).to_le_bytes());
-        self.payload.extend_from_slice(key);
-        self.payload.extend_from_slice(value);
-        self.entries += 1;
+    pub fn add_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) {
+        self.add(OP_PUT, key, seq, value);
+    }
+
+    pub fn add_delete(&mut self, key: &[u8], seq: SeqNo) {
+        self.add(OP_DELETE, key, seq, &[]);
     }
 
-    pub fn add_delete(&mut self, key: &[u8]) {
-        self.payload.push(1);
-        self.payload
-            .extend_from_slice(&(key.len() as u32).to_le_bytes());
-        self.payload.extend_from_slice(&0u32.to_le_bytes());
-        self.payload.extend_from_slice(key);
+    fn add(&mut self, op: u8, key: &[u8], seq: SeqNo, value: &[u8]) {
+        let shared = if self.since_restart == 0 {
+            self.restarts.push(self.payload.len() as u32);
+            0
+        } else {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        put_varint(&mut self.payload, shared as u64);
+        put_varint(&mut self.payload, (key.len() - shared) as u64);
+        put_varint(&mut self.payload, value.len() as u64);
+        put_varint(&mut self.payload, seq);
+        self.payload.push(op);
+        self.payload.extend_from_slice(&key[shared..]);
+        self.payload.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.since_restart = (self.since_restart + 1) % self.restart_interval;
         self.entries += 1;
     }
 
     pub fn is_full(&self) -> bool {
-        self.payload.len() >= self.target_bytes && self.entries > 0
+        self.payload.len() + 4 * (self.restarts.len() + 1) >= self.target_bytes && self.entries > 0
     }
 
     pub fn encode(self) -> Vec<u8> {
-        let mut hasher = Hasher::new();
-        hasher.update(&self.payload);
-        let crc = hasher.finalize();
         let mut out = self.payload;
-        out.extend_from_slice(&crc.to_le_bytes());
+        for restart in &self.restarts {
+            out.extend_from_slice(&restart.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
         out
     }
 
@@ -56,3 +88,253 @@ impl DataBlock {
         self.entries == 0
     }
 }
+
+/// A verified data block payload, readable in any table version.
+///
+/// Versions before 4 store full keys with fixed-width headers and no restart
+/// points, so seeking in them is a linear scan.
+#[derive(Clone)]
+pub struct Block {
+    data: Arc<[u8]>,
+    version: u32,
+    /// End of the entries, where the restart array begins.
+    entries_end: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub fn new(data: Arc<[u8]>, version: u32) -> std::io::Result<Self> {
+        let mut block = Self {
+            entries_end: data.len(),
+            data,
+            version,
+            num_restarts: 0,
+        };
+        if version >= 4 {
+            let len = block.data.len();
+            if len < 4 {
+                return Err(corrupt("short block"));
+            }
+            let n = u32::from_le_bytes(block.data[len - 4..].try_into().unwrap()) as usize;
+            let restarts_len = n
+                .checked_mul(4)
+                .and_then(|r| r.checked_add(4))
+                .filter(|&r| r <= len)
+                .ok_or_else(|| corrupt("bad restart count"))?;
+            block.entries_end = len - restarts_len;
+            block.num_restarts = n;
+        }
+        Ok(block)
+    }
+
+    /// Iterates every entry from the start of the block.
+    pub fn iter(&self) -> BlockIter {
+        BlockIter {
+            block: self.clone(),
+            pos: 0,
+            key: Vec::new(),
+            peeked: None,
+        }
+    }
+
+    /// Iterates entries starting at the first one whose key is `>= target`,
+    /// binary-searching restart points to skip most of the block.
+    pub fn seek(&self, target: &[u8]) -> BlockIter {
+        let mut iter = self.iter();
+        if self.num_restarts > 0 {
+            // Find the last restart whose key is < target; every entry before
+            // it is smaller than target too.
+            let (mut lo, mut hi) = (0usize, self.num_restarts);
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                match self.restart_key(mid) {
+                    Some(key) if key < target => lo = mid,
+                    _ => hi = mid,
+                }
+            }
+            iter.pos = self.restart_offset(lo);
+        }
+        while let Some(item) = iter.next() {
+            if item.0.as_slice() >= target {
+                iter.peeked = Some(item);
+                break;
+            }
+        }
+        iter
+    }
+
+    fn restart_offset(&self, i: usize) -> usize {
+        let at = self.entries_end + 4 * i;
+        u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    /// Full key stored at restart point `i`.
+    fn restart_key(&self, i: usize) -> Option<&[u8]> {
+        let mut pos = self.restart_offset(i);
+        let buf = &self.data[..self.entries_end];
+        let shared = get_varint(buf, &mut pos)?;
+        let unshared = get_varint(buf, &mut pos)? as usize;
+        get_varint(buf, &mut pos)?;
+        get_varint(buf, &mut pos)?;
+        let start = pos + 1;
+        if shared != 0 || start + unshared > buf.len() {
+            return None;
+        }
+        Some(&buf[start..start + unshared])
+    }
+}
+
+/// Decodes the entries of a `Block` in stored order. A malformed entry ends
+/// the iteration; the block crc makes that a sign of a format bug rather
+/// than of disk corruption.
+pub struct BlockIter {
+    block: Block,
+    pos: usize,
+    key: Vec<u8>,
+    /// Entry found by `Block::seek`, yielded before decoding resumes.
+    peeked: Option<(Vec<u8>, SeqNo, Entry)>,
+}
+
+impl BlockIter {
+    fn next_v4(&mut self) -> Option<(Vec<u8>, SeqNo, Entry)> {
+        let buf = &self.block.data[..self.block.entries_end];
+        let mut p = self.pos;
+        let shared = get_varint(buf, &mut p)? as usize;
+        let unshared = get_varint(buf, &mut p)? as usize;
+        let vlen = get_varint(buf, &mut p)? as usize;
+        let seq = get_varint(buf, &mut p)?;
+        let op = *buf.get(p)?;
+        p += 1;
+        if shared > self.key.len() || p + unshared + vlen > buf.len() {
+            return None;
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&buf[p..p + unshared]);
+        p += unshared;
+        let entry = match op {
+            OP_PUT => Entry::Put(buf[p..p + vlen].to_vec()),
+            OP_DELETE => Entry::Delete,
+            _ => return None,
+        };
+        self.pos = p + vlen;
+        Some((self.key.clone(), seq, entry))
+    }
+
+    /// Fixed-width entries of versions 1-3: `op u8 | [seq u64] | klen u32 |
+    /// vlen u32 | key | value`, where only version 3 stores the seq.
+    fn next_legacy(&mut self) -> Option<(Vec<u8>, SeqNo, Entry)> {
+        let payload = &self.block.data[..];
+        let seq_len = if self.block.version >= 3 { 8 } else { 0 };
+        let mut p = self.pos;
+        if p + 1 + seq_len + 4 + 4 > payload.len() {
+            return None;
+        }
+        let op = payload[p];
+        p += 1;
+        let seq = if seq_len > 0 {
+            u64::from_le_bytes(payload[p..p + 8].try_into().unwrap())
+        } else {
+            0
+        };
+        p += seq_len;
+        let klen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        let vlen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
+        p += 4;
+        if p + klen > payload.len() {
+            return None;
+        }
+        let k = payload[p..p + klen].to_vec();
+        p += klen;
+        let entry = if op == OP_PUT {
+            if p + vlen > payload.len() {
+                return None;
+            }
+            let v = payload[p..p + vlen].to_vec();
+            p += vlen;
+            Entry::Put(v)
+        } else {
+            Entry::Delete
+        };
+        self.pos = p;
+        Some((k, seq, entry))
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = (Vec<u8>, SeqNo, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.peeked.take() {
+            return Some(item);
+        }
+        if self.block.version >= 4 {
+            self.next_v4()
+        } else {
+            self.next_legacy()
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn corrupt(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(keys: &[String]) -> Block {
+        let mut block = DataBlock::new(64 * 1024, 4);
+        for (i, key) in keys.iter().enumerate() {
+            if i % 5 == 0 {
+                block.add_delete(key.as_bytes(), i as u64);
+            } else {
+                block.add_put(key.as_bytes(), i as u64, format!("v{i}").as_bytes());
+            }
+        }
+        Block::new(block.encode().into(), 4).unwrap()
+    }
+
+    #[test]
+    fn prefix_compressed_entries_round_trip_and_seek() {
+        let keys: Vec<String> = (0..50).map(|i| format!("user/{:04}", i * 2)).collect();
+        let block = build(&keys);
+        let decoded: Vec<_> = block.iter().collect();
+        assert_eq!(decoded.len(), keys.len());
+        for (i, (k, seq, entry)) in decoded.iter().enumerate() {
+            assert_eq!(k, keys[i].as_bytes());
+            assert_eq!(*seq, i as u64);
+            match entry {
+                Entry::Put(v) => assert_eq!(v, format!("v{i}").as_bytes()),
+                Entry::Delete => assert_eq!(i % 5, 0),
+            }
+        }
+
+        assert_eq!(block.seek(b"user/0042").next().unwrap().0, b"user/0042");
+        assert_eq!(block.seek(b"user/0043").next().unwrap().0, b"user/0044");
+        assert_eq!(block.seek(b"a").next().unwrap().0, b"user/0000");
+        assert!(block.seek(b"zzz").next().is_none());
+    }
+}
@@ -0,0 +1,113 @@
+/// A Bloom filter over the keys of one SSTable.
+///
+/// Encoded as the bit array followed by one byte holding the number of probes.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    /// Builds a filter sized for `bits_per_key` bits per key hash.
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits_per_key minimises the false positive rate.
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let nbits = (hashes.len() * bits_per_key).max(64);
+        let nbytes = nbits.div_ceil(8);
+        let nbits = (nbytes * 8) as u64;
+        let mut bits = vec![0u8; nbytes];
+        for &h in hashes {
+            let (mut h1, h2) = split(h);
+            for _ in 0..probes {
+                let bit = h1 % nbits;
+                bits[(bit / 8) as usize] |= 1 << (bit % 8);
+                h1 = h1.wrapping_add(h2);
+            }
+        }
+        Self { bits, probes }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        let nbits = (self.bits.len() * 8) as u64;
+        let (mut h1, h2) = split(bloom_hash(key));
+        for _ in 0..self.probes {
+            let bit = h1 % nbits;
+            if self.bits[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h1 = h1.wrapping_add(h2);
+        }
+        true
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.bits.len() + 1 + 4);
+        out.extend_from_slice(&self.bits);
+        out.push(self.probes as u8);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&out);
+        let crc = hasher.finalize();
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        use std::io::{Error, ErrorKind};
+        if bytes.len() < 1 + 4 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "short filter"));
+        }
+        let payload = &bytes[..bytes.len() - 4];
+        let stored_crc = u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        if hasher.finalize() != stored_crc {
+            return Err(Error::new(ErrorKind::InvalidData, "filter crc"));
+        }
+        let probes = payload[payload.len() - 1] as u32;
+        Ok(Self {
+            bits: payload[..payload.len() - 1].to_vec(),
+            probes,
+        })
+    }
+}
+
+/// 64-bit FNV-1a followed by a murmur3 finaliser to spread short keys.
+pub fn bloom_hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in key {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+fn split(h: u64) -> (u64, u64) {
+    (h & 0xffff_ffff, (h >> 32) | 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let keys: Vec<Vec<u8>> = (0..10_000u32)
+            .map(|i| format!("key{i}").into_bytes())
+            .collect();
+        let hashes: Vec<u64> = keys.iter().map(|k| bloom_hash(k)).collect();
+        let filter = BloomFilter::decode(&BloomFilter::build(&hashes, 10).encode()).unwrap();
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+
+        let false_positives = (0..10_000u32)
+            .filter(|i| filter.may_contain(format!("missing{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 300, "fp = {false_positives}");
+    }
+}
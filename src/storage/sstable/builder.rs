@@ -1,59 +1,147 @@
-use super::{BlockHandle, TableId};
+use super::{BlockHandle, TableId, TableOptions};
+use crate::storage::memtable::SeqNo;
 use crate::storage::sstable::{
-    block::DataBlock, index::Index, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION,
+    block::DataBlock,
+    bloom::{bloom_hash, BloomFilter},
+    compression::{encode_block, CompressionType},
+    index::Index,
+    properties::TableProperties,
+    range_del::{self, RangeTombstone},
+    FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION,
 };
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct SsTableBuilder {
     file: File,
     block: DataBlock,
     block_size: usize,
+    restart_interval: usize,
+    compression: CompressionType,
     index: Index,
     last_key_in_block: Vec<u8>,
+    bloom_bits_per_key: usize,
+    key_hashes: Vec<u64>,
+    range_tombstones: Vec<RangeTombstone>,
+    props: TableProperties,
 }
 
 impl SsTableBuilder {
-    pub fn new(tmp_path: &Path, block_size: usize) -> Self {
+    /// Starts table `id` at `tmp_path`; the id is recorded in the table's
+    /// properties so the file can be matched against the manifest.
+    pub fn new(tmp_path: &Path, id: TableId, opts: &TableOptions) -> std::io::Result<Self> {
+        let block_size = opts.block_bytes;
         let file = OpenOptions::new()
             .create(true)
             .truncate(true)
             .write(true)
             .read(true)
-            .open(tmp_path)
-            .expect("open tmp sstable");
-        Self {
+            .open(tmp_path)?;
+        Ok(Self {
             file,
-            block: DataBlock::new(block_size),
+            block: DataBlock::new(block_size, opts.restart_interval),
             block_size,
+            restart_interval: opts.restart_interval,
+            compression: opts.compression,
             index: Index::new(),
             last_key_in_block: Vec::new(),
-        }
+            bloom_bits_per_key: opts.bloom_bits_per_key,
+            key_hashes: Vec::new(),
+            range_tombstones: Vec::new(),
+            props: TableProperties {
+                block_bytes: block_size as u64,
+                restart_interval: opts.restart_interval as u32,
+                bloom_bits_per_key: opts.bloom_bits_per_key as u32,
+                compression: opts.compression,
+                table_id: id,
+                db_id: opts.db_id,
+                ..TableProperties::default()
+            },
+        })
     }
 
-    pub fn add_put(&mut self, key: &[u8], value: &[u8]) {
+    /// Adds a version of `key`. Keys must be added in ascending order, and
+    /// versions of the same key newest first.
+    pub fn add_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) -> std::io::Result<()> {
         if self.block.is_full() {
-            self.flush_block();
+            self.flush_block()?;
         }
-        self.block.add_put(key, value);
+        self.block.add_put(key, seq, value);
+        self.record_entry(key, seq);
+        self.props.raw_value_bytes += value.len() as u64;
+        self.push_key_hash(key);
         self.last_key_in_block.clear();
         self.last_key_in_block.extend_from_slice(key);
+        Ok(())
     }
 
-    pub fn add_delete(&mut self, key: &[u8]) {
+    pub fn add_delete(&mut self, key: &[u8], seq: SeqNo) -> std::io::Result<()> {
         if self.block.is_full() {
-            self.flush_block();
+            self.flush_block()?;
         }
-        self.block.add_delete(key);
+        self.block.add_delete(key, seq);
+        self.record_entry(key, seq);
+        self.props.num_deletions += 1;
+        self.push_key_hash(key);
         self.last_key_in_block.clear();
         self.last_key_in_block.extend_from_slice(key);
+        Ok(())
+    }
+
+    /// Adds a range tombstone, in any order relative to keys and to other
+    /// tombstones. Tombstones live in their own block and are not counted as
+    /// entries.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        let props = &mut self.props;
+        if props.num_entries == 0 && self.range_tombstones.is_empty() {
+            props.min_seq = tombstone.seq;
+            props.max_seq = tombstone.seq;
+        }
+        props.min_seq = props.min_seq.min(tombstone.seq);
+        props.max_seq = props.max_seq.max(tombstone.seq);
+        props.num_range_deletions += 1;
+        self.range_tombstones.push(tombstone);
+    }
+
+    /// Entries added so far, counting every version and tombstone.
+    pub fn num_entries(&self) -> u64 {
+        self.props.num_entries
+    }
+
+    pub fn num_deletions(&self) -> u64 {
+        self.props.num_deletions
     }
 
     pub fn finish(mut self) -> std::io::Result<(TableId, BlockHandle)> {
         if !self.block.is_empty() || !self.last_key_in_block.is_empty() {
-            self.flush_block();
+            self.flush_block()?;
         }
+        let filter_offset = self.file.seek(SeekFrom::End(0))?;
+        let mut filter_len = 0u32;
+        if self.bloom_bits_per_key > 0 && !self.key_hashes.is_empty() {
+            let filter = BloomFilter::build(&self.key_hashes, self.bloom_bits_per_key).encode();
+            self.file.write_all(&filter)?;
+            filter_len = filter.len() as u32;
+        }
+        let range_del_offset = self.file.seek(SeekFrom::End(0))?;
+        let mut range_del_len = 0u32;
+        if !self.range_tombstones.is_empty() {
+            self.range_tombstones
+                .sort_by(|a, b| a.start.cmp(&b.start).then(b.seq.cmp(&a.seq)));
+            let block = range_del::encode_block(&self.range_tombstones);
+            self.file.write_all(&block)?;
+            range_del_len = block.len() as u32;
+        }
+        self.props.largest_key = self.last_key_in_block.clone();
+        self.props.creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let props = self.props.encode();
+        let props_offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&props)?;
         let index_bytes = std::mem::take(&mut self.index).encode();
         let index_offset = self.file.seek(SeekFrom::End(0))?;
         self.file.write_all(&index_bytes)?;
@@ -61,13 +149,19 @@ impl SsTableBuilder {
         let mut footer = Vec::with_capacity(FOOTER_SIZE);
         footer.extend_from_slice(&index_offset.to_le_bytes());
         footer.extend_from_slice(&index_len.to_le_bytes());
+        footer.extend_from_slice(&filter_offset.to_le_bytes());
+        footer.extend_from_slice(&filter_len.to_le_bytes());
+        footer.extend_from_slice(&props_offset.to_le_bytes());
+        footer.extend_from_slice(&(props.len() as u32).to_le_bytes());
+        footer.extend_from_slice(&range_del_offset.to_le_bytes());
+        footer.extend_from_slice(&range_del_len.to_le_bytes());
         footer.extend_from_slice(&SSTABLE_VERSION.to_le_bytes());
         footer.extend_from_slice(&SSTABLE_MAGIC.to_le_bytes());
         self.file.write_all(&footer)?;
         self.file.flush()?;
         self.file.sync_all()?;
         Ok((
-            0 as TableId,
+            self.props.table_id,
             BlockHandle {
                 offset: index_offset,
                 length: index_len,
@@ -77,14 +171,43 @@ impl SsTableBuilder {
 }
 
 impl SsTableBuilder {
-    fn flush_block(&mut self) {
-        let start = self.file.seek(SeekFrom::End(0)).expect("seek");
-        let data = std::mem::replace(&mut self.block, DataBlock::new(self.block_size)).encode();
-        self.file.write_all(&data).expect("write block");
+    fn record_entry(&mut self, key: &[u8], seq: SeqNo) {
+        let props = &mut self.props;
+        if props.num_entries == 0 {
+            props.smallest_key = key.to_vec();
+            if self.range_tombstones.is_empty() {
+                props.min_seq = seq;
+                props.max_seq = seq;
+            }
+        }
+        props.num_entries += 1;
+        props.raw_key_bytes += key.len() as u64;
+        props.min_seq = props.min_seq.min(seq);
+        props.max_seq = props.max_seq.max(seq);
+    }
+
+    /// Hashes each distinct key once, however many versions it has.
+    fn push_key_hash(&mut self, key: &[u8]) {
+        if self.key_hashes.is_empty() || key != self.last_key_in_block.as_slice() {
+            self.key_hashes.push(bloom_hash(key));
+        }
+    }
+
+    fn flush_block(&mut self) -> std::io::Result<()> {
+        let start = self.file.seek(SeekFrom::End(0))?;
+        let raw = std::mem::replace(
+            &mut self.block,
+            DataBlock::new(self.block_size, self.restart_interval),
+        )
+        .encode();
+        let data = encode_block(&raw, self.compression)?;
+        self.file.write_all(&data)?;
         let handle = BlockHandle {
             offset: start,
             length: data.len() as u32,
         };
         self.index.add(&self.last_key_in_block, handle);
+        self.props.num_data_blocks += 1;
+        Ok(())
     }
 }
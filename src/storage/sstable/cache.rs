@@ -0,0 +1,162 @@
+use super::TableId;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Point-in-time counters of a `BlockCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// LRU cache of verified block payloads shared by every reader of an engine,
+/// keyed by (table id, block offset) and bounded by payload bytes.
+///
+/// Cloning is cheap and yields a handle onto the same cache.
+#[derive(Clone)]
+pub struct BlockCache {
+    inner: Arc<Mutex<Inner>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+/// (table id, block offset).
+type BlockKey = (TableId, u64);
+
+struct Inner {
+    capacity: usize,
+    bytes: usize,
+    tick: u64,
+    /// Cached payload and its last-use tick.
+    blocks: BTreeMap<BlockKey, (Arc<[u8]>, u64)>,
+    /// Last-use tick to key; the first entry is the least recently used.
+    lru: BTreeMap<u64, BlockKey>,
+}
+
+impl BlockCache {
+    /// Creates a cache holding up to `capacity` bytes of block payloads.
+    /// A capacity of 0 disables caching but still counts misses.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                bytes: 0,
+                tick: 0,
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get(&self, table: TableId, offset: u64) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let found = match inner.blocks.get_mut(&(table, offset)) {
+            Some((block, last_use)) => {
+                let old = std::mem::replace(last_use, tick);
+                Some((block.clone(), old))
+            }
+            None => None,
+        };
+        match found {
+            Some((block, old)) => {
+                inner.lru.remove(&old);
+                inner.lru.insert(tick, (table, offset));
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(block)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `block`, evicting least recently used blocks to stay within
+    /// capacity. Blocks larger than the whole cache are not kept.
+    pub fn insert(&self, table: TableId, offset: u64, block: Arc<[u8]>) {
+        let mut inner = self.inner.lock().unwrap();
+        if block.len() > inner.capacity {
+            return;
+        }
+        inner.remove(table, offset);
+        while inner.bytes + block.len() > inner.capacity {
+            let Some((_, (t, o))) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.remove(t, o);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.bytes += block.len();
+        inner.lru.insert(tick, (table, offset));
+        inner.blocks.insert((table, offset), (block, tick));
+    }
+
+    /// Drops every cached block of `table`, e.g. once compaction deleted it.
+    pub fn evict_table(&self, table: TableId) {
+        let mut inner = self.inner.lock().unwrap();
+        let offsets: Vec<u64> = inner
+            .blocks
+            .range((table, 0)..=(table, u64::MAX))
+            .map(|(&(_, offset), _)| offset)
+            .collect();
+        for offset in offsets {
+            inner.remove(table, offset);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let inner = self.inner.lock().unwrap();
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.blocks.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, table: TableId, offset: u64) {
+        if let Some((block, tick)) = self.blocks.remove(&(table, offset)) {
+            self.lru.remove(&tick);
+            self.bytes -= block.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Arc<[u8]> {
+        vec![0u8; len].into()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_within_capacity() {
+        let cache = BlockCache::new(300);
+        cache.insert(1, 0, block(100));
+        cache.insert(1, 100, block(100));
+        cache.insert(2, 0, block(100));
+        assert!(cache.get(1, 0).is_some());
+        cache.insert(2, 100, block(100));
+
+        assert!(cache.get(1, 100).is_none());
+        assert!(cache.get(1, 0).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!((stats.entries, stats.bytes), (3, 300));
+
+        cache.evict_table(2);
+        assert_eq!(cache.stats().entries, 1);
+        assert!(cache.get(2, 0).is_none());
+    }
+}
@@ -20,10 +20,24 @@ impl Index {
         if self.entries.is_empty() {
             return None;
         }
+        let lo = self.block_index(key);
+
+        let idx = if lo < self.entries.len() {
+            lo
+        } else {
+            self.entries.len() - 1
+        };
+
+        Some(self.entries[idx].1)
+    }
+
+    /// Position of the first block whose separator is `>= key`, or the
+    /// number of blocks if every key in the table is smaller.
+    pub fn block_index(&self, key: &[u8]) -> usize {
         let mut lo = 0usize;
         let mut hi = self.entries.len();
         while lo < hi {
-            let mid = hi + (lo - hi) / 2;
+            let mid = lo + (hi - lo) / 2;
             let (ref sep, _) = self.entries[mid];
             if key <= &sep[..] {
                 hi = mid;
@@ -31,14 +45,15 @@ impl Index {
                 lo = mid + 1;
             }
         }
+        lo
+    }
 
-        let idx = if lo < self.entries.len() {
-            lo
-        } else {
-            self.entries.len() - 1
-        };
+    pub fn handles(&self) -> Vec<BlockHandle> {
+        self.entries.iter().map(|(_, h)| *h).collect()
+    }
 
-        Some(self.entries[idx].1)
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.entries.last().map(|(k, _)| k.as_slice())
     }
 
     pub fn encode(self) -> Vec<u8> {
@@ -90,3 +105,31 @@ impl Index {
         Ok(Self { entries })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(offset: u64) -> BlockHandle {
+        BlockHandle { offset, length: 1 }
+    }
+
+    #[test]
+    fn find_block_picks_the_first_separator_not_below_the_key() {
+        let mut index = Index::new();
+        assert!(index.find_block(b"a").is_none());
+        for (i, sep) in [&b"c"[..], b"f", b"j", b"m", b"q"].iter().enumerate() {
+            index.add(sep, handle(i as u64));
+        }
+        let found = |key: &[u8]| index.find_block(key).unwrap().offset;
+        assert_eq!(found(b"a"), 0);
+        assert_eq!(found(b"c"), 0);
+        assert_eq!(found(b"d"), 1);
+        assert_eq!(found(b"j"), 2);
+        assert_eq!(found(b"p"), 4);
+        assert_eq!(found(b"z"), 4);
+
+        let index = Index::decode(&index.encode()).unwrap();
+        assert_eq!(index.find_block(b"k").unwrap().offset, 3);
+    }
+}
@@ -1,18 +1,110 @@
-/// An iterator yielding key-value pairs from an SSTable in sorted order.
-pub struct SsTableIter;
+use super::block::BlockIter;
+use super::reader::SsTableReader;
+use super::BlockHandle;
+use crate::storage::memtable::{Entry, SeqNo};
+use std::ops::Deref;
+use std::sync::Arc;
 
-impl SsTableIter {
+/// An iterator yielding entries, tombstones and older versions included, from
+/// an SSTable in (key ascending, seq descending) order. Blocks are read lazily, one at a time.
+pub struct SsTableIter<'a> {
+    reader: TableRef<'a>,
+    handles: Vec<BlockHandle>,
+    next_block: usize,
+    entries: Option<BlockIter>,
+    failed: bool,
+}
+
+impl<'a> SsTableIter<'a> {
     /// Creates a new iterator for the given reader starting at an optional key.
-    pub fn new_seek(_start: Option<&[u8]>) -> Self {
-        unimplemented!()
+    pub fn new_seek(reader: &'a SsTableReader, start: Option<&[u8]>) -> Self {
+        Self::with_reader(TableRef::Borrowed(reader), start)
+    }
+
+    fn with_reader(reader: TableRef<'a>, start: Option<&[u8]>) -> Self {
+        let mut iter = Self {
+            handles: reader.block_handles(),
+            reader,
+            next_block: 0,
+            entries: None,
+            failed: false,
+        };
+        if let Some(key) = start {
+            iter.seek(key);
+        }
+        iter
+    }
+
+    /// Repositions the iterator at the first entry whose key is `>= key`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.next_block = self.reader.block_index_for(key);
+        self.entries = None;
+        self.failed = false;
+        if self.next_block >= self.handles.len() {
+            return;
+        }
+        match self.reader.block(self.handles[self.next_block]) {
+            Ok(block) => {
+                self.next_block += 1;
+                self.entries = Some(block.seek(key));
+            }
+            Err(_) => {
+                // Leave next_block pointing at the bad block so `next`
+                // surfaces the error to the caller.
+            }
+        }
     }
 }
 
-impl Iterator for SsTableIter {
-    type Item = (Vec<u8>, Vec<u8>);
+impl SsTableIter<'static> {
+    /// Like `new_seek`, but keeps the reader alive itself, so the iterator
+    /// can outlive the table set it was taken from.
+    pub fn new_shared(reader: Arc<SsTableReader>, start: Option<&[u8]>) -> Self {
+        Self::with_reader(TableRef::Shared(reader), start)
+    }
+}
+
+impl Iterator for SsTableIter<'_> {
+    type Item = std::io::Result<(Vec<u8>, SeqNo, Entry)>;
 
     /// Advances the iterator and returns the next item if any.
     fn next(&mut self) -> Option<Self::Item> {
-        unimplemented!()
+        loop {
+            if self.failed {
+                return None;
+            }
+            if let Some(e) = self.entries.as_mut().and_then(|it| it.next()) {
+                return Some(Ok(e));
+            }
+            if self.next_block >= self.handles.len() {
+                return None;
+            }
+            match self.reader.block(self.handles[self.next_block]) {
+                Ok(block) => {
+                    self.next_block += 1;
+                    self.entries = Some(block.iter());
+                }
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+enum TableRef<'a> {
+    Borrowed(&'a SsTableReader),
+    Shared(Arc<SsTableReader>),
+}
+
+impl Deref for TableRef<'_> {
+    type Target = SsTableReader;
+
+    fn deref(&self) -> &SsTableReader {
+        match self {
+            TableRef::Borrowed(reader) => reader,
+            TableRef::Shared(reader) => reader,
+        }
     }
 }
@@ -1,9 +1,17 @@
 pub mod block;
+pub mod bloom;
 pub mod builder;
+pub mod cache;
+pub mod compression;
 pub mod index;
 pub mod iter;
+pub mod properties;
+pub mod range_del;
 pub mod reader;
 
+use compression::CompressionType;
+use std::path::{Path, PathBuf};
+
 #[derive(Copy, Clone)]
 pub struct BlockHandle {
     pub offset: u64,
@@ -12,6 +20,96 @@ pub struct BlockHandle {
 
 pub type TableId = u64;
 
-pub const SSTABLE_VERSION: u32 = 1;
+/// Outcome of looking a key up in a single table.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    Found(Vec<u8>),
+    /// The table holds a tombstone for the key; older tables must not be
+    /// consulted.
+    Deleted,
+    Absent,
+}
+
+/// What the engine tracks about a live table without opening it. It is
+/// persisted in the manifest alongside every table addition.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableMeta {
+    pub id: TableId,
+    pub level: usize,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub file_len: u64,
+    /// Entries in the table, counting every version and tombstone.
+    pub num_entries: u64,
+    /// Tombstones among `num_entries`.
+    pub num_deletions: u64,
+}
+
+impl TableMeta {
+    /// Whether `key` falls inside the table's key range.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.smallest.as_slice() <= key && key <= self.largest.as_slice()
+    }
+
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest.as_slice() <= largest && smallest <= self.largest.as_slice()
+    }
+}
+
+/// Version 2 added a Bloom filter block, referenced from the footer.
+/// Version 3 stamps every block entry with its sequence number.
+/// Version 4 prefix-compresses keys and appends restart points to each block.
+/// Version 5 tags every block with a compression type byte.
+/// Version 6 adds a properties block, referenced from the footer.
+/// Version 7 adds a range tombstone block, referenced from the footer.
+pub const SSTABLE_VERSION: u32 = 7;
 pub const SSTABLE_MAGIC: u64 = 0xF3515A5453544142;
-pub const FOOTER_SIZE: usize = 8 + 4 + 4 + 8;
+/// Footer of version-1 tables: index offset/len, version, magic.
+pub const FOOTER_SIZE_V1: usize = 8 + 4 + 4 + 8;
+/// Footer of versions 2-5: index offset/len, filter offset/len, version, magic.
+pub const FOOTER_SIZE_V2: usize = 8 + 4 + 8 + 4 + 4 + 8;
+/// Footer of version-6 tables: index offset/len, filter offset/len,
+/// properties offset/len, version, magic.
+pub const FOOTER_SIZE_V6: usize = 8 + 4 + 8 + 4 + 8 + 4 + 4 + 8;
+/// Footer of current tables: index offset/len, filter offset/len, properties
+/// offset/len, range tombstones offset/len, version, magic.
+pub const FOOTER_SIZE: usize = 8 + 4 + 8 + 4 + 8 + 4 + 8 + 4 + 4 + 8;
+
+/// Knobs that shape the tables written by `SsTableBuilder`.
+#[derive(Clone, Debug)]
+pub struct TableOptions {
+    pub block_bytes: usize,
+    /// Bloom filter bits per key; 0 writes no filter.
+    pub bloom_bits_per_key: usize,
+    /// Entries between restart points, where keys are stored in full.
+    pub restart_interval: usize,
+    pub compression: CompressionType,
+    /// Unique id of the database the tables belong to, recorded in each
+    /// table's properties; 0 when unknown.
+    pub db_id: u128,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            block_bytes: 8 * 1024,
+            bloom_bits_per_key: 10,
+            restart_interval: 16,
+            compression: CompressionType::None,
+            db_id: 0,
+        }
+    }
+}
+
+pub fn table_path(sst_dir: &Path, id: TableId) -> PathBuf {
+    sst_dir.join(format!("{id:06}.sst"))
+}
+
+pub fn table_tmp_path(sst_dir: &Path, id: TableId) -> PathBuf {
+    sst_dir.join(format!("{id:06}.sst.tmp"))
+}
+
+/// Id of a finished table from its file name, as written by `table_path`.
+pub fn table_id_from_name(name: &str) -> Option<TableId> {
+    name.strip_suffix(".sst")?.parse().ok()
+}
@@ -0,0 +1,163 @@
+use super::compression::CompressionType;
+use super::TableId;
+use crate::storage::memtable::SeqNo;
+use std::io::{Error, ErrorKind, Result};
+
+/// Summary of a table's contents and the options it was written with,
+/// stored in its properties block (table version 6 and later).
+///
+/// Encoded as fixed-width little-endian fields in declaration order, the two
+/// keys prefixed with their `u32` length, followed by a crc32 of the payload.
+/// Fields added later are appended and read as 0 from tables that lack them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// Entries in the table, counting every version and tombstone.
+    pub num_entries: u64,
+    /// Tombstones among `num_entries`.
+    pub num_deletions: u64,
+    pub num_data_blocks: u64,
+    /// Key and value bytes before prefix compression and block compression.
+    pub raw_key_bytes: u64,
+    pub raw_value_bytes: u64,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    /// Seconds since the Unix epoch when the table was finished.
+    pub creation_time: u64,
+    pub min_seq: SeqNo,
+    pub max_seq: SeqNo,
+    pub block_bytes: u64,
+    pub restart_interval: u32,
+    pub bloom_bits_per_key: u32,
+    pub compression: CompressionType,
+    /// Id the table was written under; 0 if unknown.
+    pub table_id: TableId,
+    /// Unique id of the database the table was written for; 0 if unknown.
+    pub db_id: u128,
+    /// Range tombstones in the table's range tombstone block.
+    pub num_range_deletions: u64,
+}
+
+impl TableProperties {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            8 * 12 + 16 + 4 * 4 + 1 + self.smallest_key.len() + self.largest_key.len(),
+        );
+        for v in [
+            self.num_entries,
+            self.num_deletions,
+            self.num_data_blocks,
+            self.raw_key_bytes,
+            self.raw_value_bytes,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        for key in [&self.smallest_key, &self.largest_key] {
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(key);
+        }
+        for v in [
+            self.creation_time,
+            self.min_seq,
+            self.max_seq,
+            self.block_bytes,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.restart_interval.to_le_bytes());
+        out.extend_from_slice(&self.bloom_bits_per_key.to_le_bytes());
+        out.push(self.compression as u8);
+        out.extend_from_slice(&self.table_id.to_le_bytes());
+        out.extend_from_slice(&self.db_id.to_le_bytes());
+        out.extend_from_slice(&self.num_range_deletions.to_le_bytes());
+        let crc = crc32fast::hash(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "short properties"));
+        }
+        let payload = &bytes[..bytes.len() - 4];
+        let stored_crc = u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        if crc32fast::hash(payload) != stored_crc {
+            return Err(Error::new(ErrorKind::InvalidData, "properties crc"));
+        }
+        let mut r = Fields {
+            buf: payload,
+            pos: 0,
+        };
+        let num_entries = r.u64()?;
+        let num_deletions = r.u64()?;
+        let num_data_blocks = r.u64()?;
+        let raw_key_bytes = r.u64()?;
+        let raw_value_bytes = r.u64()?;
+        let smallest_key = r.key()?;
+        let largest_key = r.key()?;
+        let creation_time = r.u64()?;
+        let min_seq = r.u64()?;
+        let max_seq = r.u64()?;
+        let block_bytes = r.u64()?;
+        let restart_interval = r.u32()?;
+        let bloom_bits_per_key = r.u32()?;
+        let compression = CompressionType::from_byte(r.take(1)?[0])?;
+        let (mut table_id, mut db_id) = (0, 0);
+        if r.pos < payload.len() {
+            table_id = r.u64()?;
+            db_id = u128::from_le_bytes(r.take(16)?.try_into().unwrap());
+        }
+        let mut num_range_deletions = 0;
+        if r.pos < payload.len() {
+            num_range_deletions = r.u64()?;
+        }
+        Ok(Self {
+            num_entries,
+            num_deletions,
+            num_data_blocks,
+            raw_key_bytes,
+            raw_value_bytes,
+            smallest_key,
+            largest_key,
+            creation_time,
+            min_seq,
+            max_seq,
+            block_bytes,
+            restart_interval,
+            bloom_bits_per_key,
+            compression,
+            table_id,
+            db_id,
+            num_range_deletions,
+        })
+    }
+}
+
+struct Fields<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "short properties"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn key(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
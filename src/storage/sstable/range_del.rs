@@ -0,0 +1,172 @@
+use crate::storage::memtable::SeqNo;
+use std::io::{Error, ErrorKind, Result};
+
+/// Deletes every version of the keys in `[start, end)` written before it.
+///
+/// A tombstone hides a version when the reader's snapshot includes the
+/// tombstone and the version is older than it; versions written afterwards
+/// are unaffected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: SeqNo,
+}
+
+impl RangeTombstone {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    /// Whether a reader at `read_seq` sees this tombstone hide version
+    /// `seq` of `key`.
+    pub fn covers(&self, key: &[u8], seq: SeqNo, read_seq: SeqNo) -> bool {
+        self.seq <= read_seq && seq < self.seq && self.contains(key)
+    }
+
+    /// The part of the tombstone inside `[lower, upper)`, where `None`
+    /// leaves that side open; `None` if nothing is left.
+    pub fn clip(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<RangeTombstone> {
+        let start = match lower {
+            Some(lower) if lower > self.start.as_slice() => lower,
+            _ => &self.start,
+        };
+        let end = match upper {
+            Some(upper) if upper < self.end.as_slice() => upper,
+            _ => &self.end,
+        };
+        (start < end).then(|| RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq: self.seq,
+        })
+    }
+}
+
+/// Sequence number of the newest tombstone in `tombstones` that covers
+/// `key` for a reader at `read_seq`.
+pub fn newest_covering(
+    tombstones: &[RangeTombstone],
+    key: &[u8],
+    read_seq: SeqNo,
+) -> Option<SeqNo> {
+    tombstones
+        .iter()
+        .filter(|t| t.seq <= read_seq && t.contains(key))
+        .map(|t| t.seq)
+        .max()
+}
+
+/// Smallest and largest key a table holding point keys `points` (first and
+/// last, if any) and `tombstones` may answer for. A tombstone's exclusive
+/// end is counted as included, which only widens the range.
+pub fn key_bounds(
+    points: Option<(Vec<u8>, Vec<u8>)>,
+    tombstones: &[RangeTombstone],
+) -> (Vec<u8>, Vec<u8>) {
+    let mut bounds = points;
+    for t in tombstones {
+        bounds = Some(match bounds {
+            Some((smallest, largest)) => {
+                (smallest.min(t.start.clone()), largest.max(t.end.clone()))
+            }
+            None => (t.start.clone(), t.end.clone()),
+        });
+    }
+    bounds.unwrap_or_default()
+}
+
+/// Encodes a table's range tombstone block: `count u32`, then per tombstone
+/// `seq u64 | start_len u32 | end_len u32 | start | end`, then a crc32 of
+/// everything before it.
+pub fn encode_block(tombstones: &[RangeTombstone]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(tombstones.len() as u32).to_le_bytes());
+    for t in tombstones {
+        out.extend_from_slice(&t.seq.to_le_bytes());
+        out.extend_from_slice(&(t.start.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(t.end.len() as u32).to_le_bytes());
+        out.extend_from_slice(&t.start);
+        out.extend_from_slice(&t.end);
+    }
+    let crc = crc32fast::hash(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+pub fn decode_block(bytes: &[u8]) -> Result<Vec<RangeTombstone>> {
+    let short = || Error::new(ErrorKind::InvalidData, "short range tombstone block");
+    if bytes.len() < 4 + 4 {
+        return Err(short());
+    }
+    let payload = &bytes[..bytes.len() - 4];
+    let stored_crc = u32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+    if crc32fast::hash(payload) != stored_crc {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "range tombstone block crc",
+        ));
+    }
+    let count = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    let mut p = 4;
+    let mut tombstones = Vec::new();
+    for _ in 0..count {
+        let header = payload.get(p..p + 16).ok_or_else(short)?;
+        let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let start_len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let end_len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        p += 16;
+        let start = payload.get(p..p + start_len).ok_or_else(short)?.to_vec();
+        p += start_len;
+        let end = payload.get(p..p + end_len).ok_or_else(short)?.to_vec();
+        p += end_len;
+        tombstones.push(RangeTombstone { start, end, seq });
+    }
+    Ok(tombstones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(start: &[u8], end: &[u8], seq: SeqNo) -> RangeTombstone {
+        RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        }
+    }
+
+    #[test]
+    fn covers_only_older_versions_inside_the_range() {
+        let t = ts(b"b", b"d", 10);
+        assert!(t.covers(b"b", 9, 10));
+        assert!(t.covers(b"c\xff", 1, u64::MAX));
+        assert!(!t.covers(b"d", 9, 10));
+        assert!(!t.covers(b"a", 9, 10));
+        assert!(!t.covers(b"c", 11, 20));
+        assert!(!t.covers(b"c", 9, 9));
+        assert_eq!(
+            newest_covering(&[t.clone(), ts(b"a", b"z", 5)], b"c", 9),
+            Some(5)
+        );
+
+        assert_eq!(t.clip(Some(b"c"), None), Some(ts(b"c", b"d", 10)));
+        assert_eq!(t.clip(None, Some(b"bb")), Some(ts(b"b", b"bb", 10)));
+        assert_eq!(t.clip(Some(b"d"), None), None);
+        assert_eq!(
+            key_bounds(Some((b"c".to_vec(), b"c".to_vec())), &[t]),
+            (b"b".to_vec(), b"d".to_vec())
+        );
+    }
+
+    #[test]
+    fn block_round_trips_and_rejects_corruption() {
+        let tombstones = vec![ts(b"a", b"c", 3), ts(b"", b"\xff\xff", 7)];
+        let mut block = encode_block(&tombstones);
+        assert_eq!(decode_block(&block).unwrap(), tombstones);
+        assert!(decode_block(&encode_block(&[])).unwrap().is_empty());
+        block[6] ^= 1;
+        assert!(decode_block(&block).is_err());
+    }
+}
@@ -1,63 +1,239 @@
-use super::TableId;
-use crate::storage::sstable::{index::Index, FOOTER_SIZE, SSTABLE_MAGIC, SSTABLE_VERSION};
+use super::block::Block;
+use super::cache::BlockCache;
+use super::compression;
+use super::iter::SsTableIter;
+use super::properties::TableProperties;
+use super::range_del::{self, RangeTombstone};
+use super::{BlockHandle, Lookup, TableId};
+use crate::storage::memtable::{Entry, SeqNo, MAX_SEQ};
+use crate::storage::sstable::{
+    bloom::BloomFilter, index::Index, FOOTER_SIZE, FOOTER_SIZE_V1, FOOTER_SIZE_V2, FOOTER_SIZE_V6,
+    SSTABLE_MAGIC, SSTABLE_VERSION,
+};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
 
+/// An open table. Blocks are read with positional reads, so one reader
+/// serves any number of threads at once.
 pub struct SsTableReader {
     file: File,
     index: Index,
+    filter: Option<BloomFilter>,
+    version: u32,
+    properties: Option<TableProperties>,
+    /// Read in full when the table is opened; there are few of them.
+    range_tombstones: Vec<RangeTombstone>,
+    id: TableId,
+    cache: Option<BlockCache>,
 }
 
 impl SsTableReader {
+    /// Opens a table without a block cache, taking its id from its
+    /// properties when it has them.
     pub fn open(path: &Path) -> std::io::Result<Self> {
-        let mut file = File::open(path)?;
+        let mut reader = Self::open_inner(path, 0, None)?;
+        if let Some(props) = &reader.properties {
+            reader.id = props.table_id;
+        }
+        Ok(reader)
+    }
+
+    /// Opens table `id`, serving block reads through the shared `cache`.
+    /// Fails if the file records a different table id, i.e. it was renamed.
+    pub fn open_cached(path: &Path, id: TableId, cache: &BlockCache) -> std::io::Result<Self> {
+        let reader = Self::open_inner(path, id, Some(cache.clone()))?;
+        match reader.properties.as_ref().map(|p| p.table_id) {
+            Some(stored) if stored != 0 && stored != id => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} holds table {stored}, not table {id}", path.display()),
+            )),
+            _ => Ok(reader),
+        }
+    }
+
+    fn open_inner(path: &Path, id: TableId, cache: Option<BlockCache>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
         let len = file.metadata()?.len();
-        if len < FOOTER_SIZE as u64 {
+        if len < FOOTER_SIZE_V1 as u64 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
                 "short sstable",
             ));
         }
-        file.seek(SeekFrom::Start(len - FOOTER_SIZE as u64))?;
-        let mut footer = [0u8; 24];
-        file.read_exact(&mut footer)?;
-        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
-        let index_len = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as usize;
-        let version = u32::from_le_bytes(footer[12..16].try_into().unwrap());
-        let magic = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        // Every footer version ends in `version u32 | magic u64`.
+        let trailer = read_at(&file, len - 12, 12)?;
+        let version = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let magic = u64::from_le_bytes(trailer[4..12].try_into().unwrap());
         if magic != SSTABLE_MAGIC {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "bad magic",
             ));
         }
-        if version != SSTABLE_VERSION {
+        let footer_size = match version {
+            1 => FOOTER_SIZE_V1,
+            2..=5 => FOOTER_SIZE_V2,
+            6 => FOOTER_SIZE_V6,
+            7..=SSTABLE_VERSION => FOOTER_SIZE,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "bad version",
+                ))
+            }
+        };
+        if len < footer_size as u64 {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "bad version",
+                std::io::ErrorKind::UnexpectedEof,
+                "short sstable",
             ));
         }
-        file.seek(SeekFrom::Start(index_offset))?;
-        let mut index_buf = vec![0u8; index_len];
-        file.read_exact(&mut index_buf)?;
-        let index = Index::decode(&index_buf[..])?;
-        Ok(Self { file, index })
+        let footer = read_at(&file, len - footer_size as u64, footer_size)?;
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let index_len = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as usize;
+        let index = Index::decode(&read_at(&file, index_offset, index_len)?)?;
+
+        let mut filter = None;
+        if version >= 2 {
+            let filter_offset = u64::from_le_bytes(footer[12..20].try_into().unwrap());
+            let filter_len = u32::from_le_bytes(footer[20..24].try_into().unwrap()) as usize;
+            if filter_len > 0 {
+                let filter_buf = read_at(&file, filter_offset, filter_len)?;
+                filter = Some(BloomFilter::decode(&filter_buf)?);
+            }
+        }
+
+        let mut properties = None;
+        if version >= 6 {
+            let props_offset = u64::from_le_bytes(footer[24..32].try_into().unwrap());
+            let props_len = u32::from_le_bytes(footer[32..36].try_into().unwrap()) as usize;
+            let props_buf = read_at(&file, props_offset, props_len)?;
+            properties = Some(TableProperties::decode(&props_buf)?);
+        }
+
+        let mut range_tombstones = Vec::new();
+        if version >= 7 {
+            let offset = u64::from_le_bytes(footer[36..44].try_into().unwrap());
+            let len = u32::from_le_bytes(footer[44..48].try_into().unwrap()) as usize;
+            if len > 0 {
+                range_tombstones = range_del::decode_block(&read_at(&file, offset, len)?)?;
+            }
+        }
+        Ok(Self {
+            file,
+            index,
+            filter,
+            version,
+            properties,
+            range_tombstones,
+            id,
+            cache,
+        })
+    }
+
+    /// False when the table's Bloom filter proves `key` is absent.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.may_contain(key))
+    }
+
+    /// Contents summary written by the builder; `None` for tables older
+    /// than version 6.
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.properties.as_ref()
+    }
+
+    /// Unique id of the database the table was written for, if recorded.
+    pub fn db_id(&self) -> Option<u128> {
+        self.properties
+            .as_ref()
+            .map(|p| p.db_id)
+            .filter(|&id| id != 0)
     }
 
     pub fn table_id(&self) -> TableId {
-        0
+        self.id
     }
 
-    pub fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
-        let handle = match self.index.find_block(key) {
-            Some(h) => h,
-            None => return Ok(None),
-        };
-        let mut buf = vec![0u8; handle.length as usize];
-        let mut f = &self.file;
-        f.seek(SeekFrom::Start(handle.offset))?;
-        f.read_exact(&mut buf)?;
+    /// The table's range tombstones, ordered by start key.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    pub fn get(&self, key: &[u8]) -> std::io::Result<Lookup> {
+        self.get_at(key, MAX_SEQ)
+    }
+
+    /// Newest version of `key` with a sequence number `<= seq`, or
+    /// `Deleted` if one of the table's range tombstones is newer. Versions
+    /// of one key may straddle a block boundary, so this walks an iterator.
+    pub fn get_at(&self, key: &[u8], seq: SeqNo) -> std::io::Result<Lookup> {
+        let covered = range_del::newest_covering(&self.range_tombstones, key, seq);
+        if self.may_contain(key) {
+            for item in SsTableIter::new_seek(self, Some(key)) {
+                let (k, s, entry) = item?;
+                if k != key {
+                    break;
+                }
+                if s <= seq {
+                    if covered.is_some_and(|t| t > s) {
+                        break;
+                    }
+                    return Ok(match entry {
+                        Entry::Put(v) => Lookup::Found(v),
+                        Entry::Delete => Lookup::Deleted,
+                    });
+                }
+            }
+        }
+        Ok(match covered {
+            Some(_) => Lookup::Deleted,
+            None => Lookup::Absent,
+        })
+    }
+
+    /// Block handles in key order, as recorded in the index.
+    pub fn block_handles(&self) -> Vec<BlockHandle> {
+        self.index.handles()
+    }
+
+    /// Position in `block_handles` of the first block that may hold `key`.
+    pub fn block_index_for(&self, key: &[u8]) -> usize {
+        self.index.block_index(key)
+    }
+
+    /// Smallest key stored in the table, read from its first block.
+    pub fn first_key(&self) -> std::io::Result<Option<Vec<u8>>> {
+        match self.index.handles().first() {
+            Some(&h) => Ok(self.block(h)?.iter().next().map(|(k, _, _)| k)),
+            None => Ok(None),
+        }
+    }
+
+    /// Largest key stored in the table, taken from the last index separator.
+    pub fn last_key(&self) -> Option<Vec<u8>> {
+        self.index.last_key().map(|k| k.to_vec())
+    }
+
+    /// Returns the verified payload of a data block, from the block cache
+    /// when possible.
+    pub fn read_block(&self, handle: BlockHandle) -> std::io::Result<Arc<[u8]>> {
+        if let Some(cache) = &self.cache {
+            if let Some(block) = cache.get(self.id, handle.offset) {
+                return Ok(block);
+            }
+        }
+        let block: Arc<[u8]> = self.read_block_uncached(handle)?.into();
+        if let Some(cache) = &self.cache {
+            cache.insert(self.id, handle.offset, block.clone());
+        }
+        Ok(block)
+    }
+
+    /// Reads a data block from disk, verifies its crc over the stored bytes
+    /// and, from version 5 on, decompresses it.
+    fn read_block_uncached(&self, handle: BlockHandle) -> std::io::Result<Vec<u8>> {
+        let mut buf = read_at(&self.file, handle.offset, handle.length as usize)?;
         if buf.len() < 4 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::UnexpectedEof,
@@ -65,9 +241,9 @@ impl SsTableReader {
             ));
         }
         let crc_stored = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
-        let payload = &buf[..buf.len() - 4];
+        buf.truncate(buf.len() - 4);
         let mut hasher = crc32fast::Hasher::new();
-        hasher.update(payload);
+        hasher.update(&buf);
         let crc_calc = hasher.finalize();
         if crc_calc != crc_stored {
             return Err(std::io::Error::new(
@@ -75,40 +251,222 @@ impl SsTableReader {
                 "block crc",
             ));
         }
-        let mut p = 0usize;
-        let mut found: Option<(u8, Vec<u8>)> = None;
-        while p < payload.len() {
-            if p + 1 + 4 + 4 > payload.len() {
-                break;
-            }
-            let op = payload[p];
-            p += 1;
-            let klen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
-            p += 4;
-            let vlen = u32::from_le_bytes(payload[p..p + 4].try_into().unwrap()) as usize;
-            p += 4;
-            if p + klen > payload.len() {
-                break;
-            }
-            let k = &payload[p..p + klen];
-            p += klen;
-            if op == 0 {
-                if p + vlen > payload.len() {
-                    break;
-                }
-                let v = &payload[p..p + vlen];
-                p += vlen;
-                if k == key {
-                    found = Some((0, v.to_vec()));
-                }
-            } else if k == key {
-                found = Some((1, Vec::new()));
+        if self.version >= 5 {
+            return compression::decode_block(&buf);
+        }
+        Ok(buf)
+    }
+
+    /// Reads the block at `handle` for decoding in this table's format.
+    pub fn block(&self, handle: BlockHandle) -> std::io::Result<Block> {
+        Block::new(self.read_block(handle)?, self.version)
+    }
+}
+
+/// Reads `len` bytes at `offset` without moving the file's cursor, so any
+/// number of threads can read one table through a shared `&File`.
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    use std::os::windows::fs::FileExt;
+    let mut buf = vec![0u8; len];
+    let mut done = 0;
+    while done < len {
+        match file.seek_read(&mut buf[done..], offset + done as u64) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sstable::{builder::SsTableBuilder, TableOptions};
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("zynk-reader-{name}-{}.sst", std::process::id()))
+    }
+
+    #[test]
+    fn reads_version_1_tables_without_filter() {
+        let path = temp_path("v1");
+        // v1 entries: op u8 | klen u32 | vlen u32 | key | value, then a crc.
+        let mut data = Vec::new();
+        for (op, key, value) in [(0u8, b"a", &b"1"[..]), (1u8, b"b", &b""[..])] {
+            data.push(op);
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        let crc = crc32fast::hash(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        let mut index = Index::new();
+        index.add(
+            b"b",
+            BlockHandle {
+                offset: 0,
+                length: data.len() as u32,
+            },
+        );
+        let index_bytes = index.encode();
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(&data).unwrap();
+        file.write_all(&index_bytes).unwrap();
+        file.write_all(&(data.len() as u64).to_le_bytes()).unwrap();
+        file.write_all(&(index_bytes.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(&1u32.to_le_bytes()).unwrap();
+        file.write_all(&SSTABLE_MAGIC.to_le_bytes()).unwrap();
+        drop(file);
+
+        let reader = SsTableReader::open(&path).unwrap();
+        assert!(reader.may_contain(b"zzz"));
+        assert_eq!(reader.get(b"a").unwrap(), Lookup::Found(b"1".to_vec()));
+        assert_eq!(reader.get(b"b").unwrap(), Lookup::Deleted);
+        assert_eq!(reader.get(b"c").unwrap(), Lookup::Absent);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn bloom_filter_rejects_absent_keys() {
+        let path = temp_path("bloom");
+        let mut builder = SsTableBuilder::new(&path, 1, &TableOptions::default()).unwrap();
+        for i in 0..1000u32 {
+            builder
+                .add_put(format!("key{i:05}").as_bytes(), i as u64 + 1, b"v")
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let reader = SsTableReader::open(&path).unwrap();
+        assert_eq!(
+            reader.get(b"key00042").unwrap(),
+            Lookup::Found(b"v".to_vec())
+        );
+        let rejected = (0..1000u32)
+            .filter(|i| !reader.may_contain(format!("nope{i}").as_bytes()))
+            .count();
+        assert!(rejected > 950, "rejected = {rejected}");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn properties_describe_the_table() {
+        let path = temp_path("props");
+        let opts = TableOptions {
+            block_bytes: 256,
+            ..TableOptions::default()
+        };
+        let mut builder = SsTableBuilder::new(&path, 7, &opts).unwrap();
+        for i in 0..100u32 {
+            let key = format!("key{i:03}");
+            if i % 10 == 0 {
+                builder.add_delete(key.as_bytes(), 500 + i as u64).unwrap();
+            } else {
+                builder
+                    .add_put(key.as_bytes(), 500 + i as u64, b"value")
+                    .unwrap();
             }
         }
-        match found {
-            Some((0, v)) => Ok(Some(v)),
-            Some((1, _)) => Ok(None),
-            _ => Ok(None),
+        builder.finish().unwrap();
+
+        let reader = SsTableReader::open(&path).unwrap();
+        let props = reader.properties().unwrap();
+        assert_eq!((props.num_entries, props.num_deletions), (100, 10));
+        assert_eq!(props.num_data_blocks, reader.block_handles().len() as u64);
+        assert_eq!(props.raw_key_bytes, 600);
+        assert_eq!(props.raw_value_bytes, 450);
+        assert_eq!(props.smallest_key, b"key000");
+        assert_eq!(props.largest_key, b"key099");
+        assert_eq!((props.min_seq, props.max_seq), (500, 599));
+        assert_eq!((props.block_bytes, props.restart_interval), (256, 16));
+        assert!(props.creation_time > 0);
+        assert_eq!(props.table_id, 7);
+        assert_eq!(reader.table_id(), 7);
+        assert_eq!(
+            reader.get(b"key042").unwrap(),
+            Lookup::Found(b"value".to_vec())
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn range_tombstones_hide_older_versions_in_the_table() {
+        let path = temp_path("range-del");
+        let mut builder = SsTableBuilder::new(&path, 1, &TableOptions::default()).unwrap();
+        builder.add_put(b"a", 5, b"old").unwrap();
+        builder.add_put(b"b", 9, b"new").unwrap();
+        builder.add_put(b"b", 5, b"old").unwrap();
+        builder.add_range_tombstone(RangeTombstone {
+            start: b"a".to_vec(),
+            end: b"c".to_vec(),
+            seq: 7,
+        });
+        builder.finish().unwrap();
+
+        let reader = SsTableReader::open(&path).unwrap();
+        assert_eq!(reader.range_tombstones().len(), 1);
+        let props = reader.properties().unwrap();
+        assert_eq!((props.num_entries, props.num_range_deletions), (3, 1));
+        assert_eq!((props.min_seq, props.max_seq), (5, 9));
+        assert_eq!(reader.get(b"a").unwrap(), Lookup::Deleted);
+        assert_eq!(reader.get(b"b").unwrap(), Lookup::Found(b"new".to_vec()));
+        assert_eq!(reader.get_at(b"b", 8).unwrap(), Lookup::Deleted);
+        assert_eq!(
+            reader.get_at(b"a", 6).unwrap(),
+            Lookup::Found(b"old".to_vec())
+        );
+        // No point key at all, so the Bloom filter rejects it, but the
+        // tombstone still answers.
+        assert_eq!(reader.get(b"bb").unwrap(), Lookup::Deleted);
+        assert_eq!(reader.get(b"c").unwrap(), Lookup::Absent);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concurrent_gets_read_their_own_blocks() {
+        let path = temp_path("concurrent");
+        let opts = TableOptions {
+            block_bytes: 128,
+            ..TableOptions::default()
+        };
+        let mut builder = SsTableBuilder::new(&path, 1, &opts).unwrap();
+        for i in 0..2000u32 {
+            builder
+                .add_put(
+                    format!("key{i:05}").as_bytes(),
+                    1,
+                    format!("v{i}").as_bytes(),
+                )
+                .unwrap();
         }
+        builder.finish().unwrap();
+
+        let reader = SsTableReader::open(&path).unwrap();
+        std::thread::scope(|s| {
+            for t in 0..8u32 {
+                let reader = &reader;
+                s.spawn(move || {
+                    for i in (t..2000).step_by(8) {
+                        let got = reader.get(format!("key{i:05}").as_bytes()).unwrap();
+                        assert_eq!(got, Lookup::Found(format!("v{i}").into_bytes()));
+                    }
+                });
+            }
+        });
+        let _ = std::fs::remove_file(&path);
     }
 }
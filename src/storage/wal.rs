@@ -0,0 +1,666 @@
+use crate::storage::manifest::fsync_dir;
+use crate::storage::memtable::{MemTable, SeqNo};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+const OP_BATCH: u8 = 2;
+/// Encoded like a put, with the range's start as the key and its
+/// exclusive end as the value.
+const OP_RANGE_DELETE: u8 = 3;
+
+/// Size of a put or delete before its key and value.
+const OP_HEADER_SIZE: usize = 1 + 8 + 4 + 4;
+
+/// One write as logged in a batch record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoggedOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// Deletes every key in `[start, end)`.
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+impl LoggedOp {
+    /// The op code, key and value it is encoded with.
+    fn parts(&self) -> (u8, &[u8], &[u8]) {
+        match self {
+            LoggedOp::Put(key, value) => (OP_PUT, key, value),
+            LoggedOp::Delete(key) => (OP_DELETE, key, &[]),
+            LoggedOp::DeleteRange(start, end) => (OP_RANGE_DELETE, start, end),
+        }
+    }
+}
+
+/// Header written before every record: payload length followed by its crc32.
+const RECORD_HEADER_SIZE: usize = 4 + 4;
+
+/// Controls when appended WAL records are fsynced to stable storage.
+///
+/// Every record is handed to the OS before the memtable is mutated, so a
+/// process crash never loses an acknowledged write; the policy only decides
+/// how much can be lost on power failure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// fsync after every record.
+    #[default]
+    Always,
+    /// fsync from a background thread once per interval, if anything was
+    /// written since the last one.
+    GroupCommit(Duration),
+    /// Never fsync; rely on the OS to write back dirty pages.
+    Never,
+}
+
+/// Appends CRC-framed records to a single WAL segment.
+///
+/// Record layout: `len u32 | crc32(payload) u32 | payload`, where the payload
+/// uses the same `op | seq | klen | vlen | key | value` encoding as data blocks,
+/// or is a batch: `OP_BATCH | count u32` followed by `count` such encodings.
+///
+/// Once a write or fsync fails the segment may end in a partial record, so
+/// every later append and sync fails too.
+pub struct WalWriter {
+    file: File,
+    path: PathBuf,
+    id: u64,
+    policy: WalSyncPolicy,
+    unsynced: bool,
+    group: Option<GroupSync>,
+    failed: Option<(ErrorKind, String)>,
+}
+
+/// The background fsync of a `GroupCommit` segment.
+struct GroupSync {
+    shared: Arc<GroupShared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct GroupShared {
+    state: Mutex<GroupState>,
+    stop: Condvar,
+}
+
+#[derive(Default)]
+struct GroupState {
+    /// Something was written since the last background fsync.
+    dirty: bool,
+    stopped: bool,
+    failed: Option<(ErrorKind, String)>,
+}
+
+impl GroupSync {
+    fn spawn(file: File, interval: Duration) -> Self {
+        let shared = Arc::new(GroupShared {
+            state: Mutex::new(GroupState::default()),
+            stop: Condvar::new(),
+        });
+        let worker = Arc::clone(&shared);
+        let handle = std::thread::spawn(move || {
+            let mut state = worker.state.lock().unwrap();
+            loop {
+                state = worker.stop.wait_timeout(state, interval).unwrap().0;
+                if state.stopped {
+                    return;
+                }
+                if !state.dirty {
+                    continue;
+                }
+                state.dirty = false;
+                drop(state);
+                let synced = file.sync_data();
+                state = worker.state.lock().unwrap();
+                if let Err(e) = synced {
+                    state.failed = Some((e.kind(), e.to_string()));
+                    return;
+                }
+            }
+        });
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for GroupSync {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.stop.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl WalWriter {
+    pub fn create(path: PathBuf, id: u64, policy: WalSyncPolicy) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)?;
+        fsync_dir(&path)?;
+        let group = match policy {
+            WalSyncPolicy::GroupCommit(interval) => {
+                Some(GroupSync::spawn(file.try_clone()?, interval))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            file,
+            path,
+            id,
+            policy,
+            unsynced: false,
+            group,
+            failed: None,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append_put(&mut self, key: &[u8], seq: SeqNo, value: &[u8]) -> Result<()> {
+        let mut payload = Vec::with_capacity(OP_HEADER_SIZE + key.len() + value.len());
+        encode_op(&mut payload, OP_PUT, key, seq, value);
+        self.append_record(&payload)
+    }
+
+    pub fn append_delete(&mut self, key: &[u8], seq: SeqNo) -> Result<()> {
+        let mut payload = Vec::with_capacity(OP_HEADER_SIZE + key.len());
+        encode_op(&mut payload, OP_DELETE, key, seq, &[]);
+        self.append_record(&payload)
+    }
+
+    /// Appends ops as one record, numbered from `first_seq` up, so replay
+    /// applies all of them or none. A single op is written as a plain
+    /// record of its own.
+    pub fn append_batch(&mut self, first_seq: SeqNo, ops: &[LoggedOp]) -> Result<()> {
+        let size = ops
+            .iter()
+            .map(|op| {
+                let (_, key, value) = op.parts();
+                OP_HEADER_SIZE + key.len() + value.len()
+            })
+            .sum::<usize>();
+        let mut payload = Vec::with_capacity(1 + 4 + size);
+        if ops.len() != 1 {
+            payload.push(OP_BATCH);
+            payload.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        }
+        for (seq, op) in (first_seq..).zip(ops) {
+            let (kind, key, value) = op.parts();
+            encode_op(&mut payload, kind, key, seq, value);
+        }
+        self.append_record(&payload)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.check()?;
+        if self.unsynced {
+            if let Err(e) = self.file.sync_data() {
+                return Err(self.fail(e));
+            }
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Fails if an earlier write or fsync of this segment did.
+    pub fn check(&mut self) -> Result<()> {
+        if self.failed.is_none() {
+            if let Some(group) = &self.group {
+                self.failed = group.shared.state.lock().unwrap().failed.clone();
+            }
+        }
+        match &self.failed {
+            Some((kind, msg)) => Err(Error::new(
+                *kind,
+                format!("wal segment {} failed earlier: {msg}", self.path.display()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, e: Error) -> Error {
+        self.failed = Some((e.kind(), e.to_string()));
+        e
+    }
+
+    fn append_record(&mut self, payload: &[u8]) -> Result<()> {
+        self.check()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        let crc = hasher.finalize();
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf.extend_from_slice(payload);
+        if let Err(e) = self.file.write_all(&buf) {
+            return Err(self.fail(e));
+        }
+        self.unsynced = true;
+        match self.policy {
+            WalSyncPolicy::Always => self.sync(),
+            WalSyncPolicy::GroupCommit(_) => {
+                if let Some(group) = &self.group {
+                    group.shared.state.lock().unwrap().dirty = true;
+                }
+                Ok(())
+            }
+            WalSyncPolicy::Never => Ok(()),
+        }
+    }
+}
+
+impl Drop for WalWriter {
+    fn drop(&mut self) {
+        self.group = None;
+        let _ = self.sync();
+    }
+}
+
+/// Replays every intact record of a WAL segment into `mem`.
+///
+/// A bad record that runs to the end of the file, or is followed only by
+/// zeros the filesystem allocated for it, is what a write torn by a crash
+/// looks like. It is skipped in the `last` segment, the only one still
+/// being written; anywhere else the segment is `InvalidData`.
+pub fn replay_wal(path: &Path, mem: &MemTable, last: bool) -> Result<usize> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let mut applied = 0usize;
+    scan_records(&bytes, path, last, |ops| {
+        apply_ops(ops, mem);
+        applied += 1;
+    })?;
+    Ok(applied)
+}
+
+/// Cuts a torn record off the end of a segment, so it still replays once a
+/// newer segment exists. Corruption before the tail is `InvalidData`.
+pub fn trim_torn_tail(path: &Path) -> Result<()> {
+    let bytes = fs::read(path)?;
+    let valid_len = scan_records(&bytes, path, true, |_| {})?;
+    if valid_len < bytes.len() {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(valid_len as u64)?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Hands the ops of each intact record to `each`, returning the length of
+/// the intact prefix. See `replay_wal` for what `last` forgives.
+fn scan_records<'a>(
+    bytes: &'a [u8],
+    path: &Path,
+    last: bool,
+    mut each: impl FnMut(Vec<DecodedOp<'a>>),
+) -> Result<usize> {
+    let mut p = 0usize;
+    while p < bytes.len() {
+        let Some((ops, end)) = decode_record(bytes, p) else {
+            if last && is_torn_tail(bytes, p) {
+                break;
+            }
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("wal segment {} corrupt at offset {p}", path.display()),
+            ));
+        };
+        each(ops);
+        p = end;
+    }
+    Ok(p)
+}
+
+/// Decodes the record at offset `p`, returning its ops and where it ends.
+fn decode_record(bytes: &[u8], p: usize) -> Option<(Vec<DecodedOp<'_>>, usize)> {
+    if bytes.len() - p < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[p..p + 4].try_into().unwrap()) as usize;
+    let crc_stored = u32::from_le_bytes(bytes[p + 4..p + 8].try_into().unwrap());
+    let start = p + RECORD_HEADER_SIZE;
+    if len > bytes.len() - start {
+        return None;
+    }
+    let payload = &bytes[start..start + len];
+    if crc32fast::hash(payload) != crc_stored {
+        return None;
+    }
+    Some((decode_ops(payload)?, start + len))
+}
+
+/// Whether the bad record at offset `p` was the last append: it reaches the
+/// end of the file, or only zeros follow where the next record would start.
+fn is_torn_tail(bytes: &[u8], p: usize) -> bool {
+    if bytes.len() - p < RECORD_HEADER_SIZE {
+        return true;
+    }
+    let len = u32::from_le_bytes(bytes[p..p + 4].try_into().unwrap()) as usize;
+    let end = (p + RECORD_HEADER_SIZE).saturating_add(len);
+    end >= bytes.len() || bytes[end..].iter().all(|&b| b == 0)
+}
+
+fn encode_op(out: &mut Vec<u8>, kind: u8, key: &[u8], seq: SeqNo, value: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(value);
+}
+
+/// One op decoded from a record: `(op, key, seq, value)`.
+type DecodedOp<'a> = (u8, &'a [u8], SeqNo, &'a [u8]);
+
+/// Decodes the op at the start of `buf`, returning it and the bytes it
+/// took.
+fn decode_op(buf: &[u8]) -> Option<(DecodedOp<'_>, usize)> {
+    if buf.len() < OP_HEADER_SIZE {
+        return None;
+    }
+    let seq = u64::from_le_bytes(buf[1..9].try_into().unwrap());
+    let klen = u32::from_le_bytes(buf[9..13].try_into().unwrap()) as usize;
+    let vlen = u32::from_le_bytes(buf[13..17].try_into().unwrap()) as usize;
+    let p = OP_HEADER_SIZE;
+    if p + klen + vlen > buf.len() {
+        return None;
+    }
+    if !matches!(buf[0], OP_PUT | OP_DELETE | OP_RANGE_DELETE) {
+        return None;
+    }
+    let key = &buf[p..p + klen];
+    let value = &buf[p + klen..p + klen + vlen];
+    Some(((buf[0], key, seq, value), p + klen + vlen))
+}
+
+/// Decodes every op of a record payload, or `None` if any part of it is
+/// malformed.
+fn decode_ops(payload: &[u8]) -> Option<Vec<DecodedOp<'_>>> {
+    let mut ops = Vec::new();
+    if payload.first() == Some(&OP_BATCH) {
+        if payload.len() < 1 + 4 {
+            return None;
+        }
+        let count = u32::from_le_bytes(payload[1..5].try_into().unwrap());
+        let mut p = 1 + 4;
+        for _ in 0..count {
+            let (op, len) = decode_op(&payload[p..])?;
+            ops.push(op);
+            p += len;
+        }
+    } else {
+        ops.push(decode_op(payload)?.0);
+    }
+    Some(ops)
+}
+
+fn apply_ops(ops: Vec<DecodedOp<'_>>, mem: &MemTable) {
+    for (kind, key, seq, value) in ops {
+        match kind {
+            OP_PUT => mem.put(key, seq, value),
+            OP_DELETE => mem.delete(key, seq),
+            _ => mem.delete_range(key, value, seq),
+        }
+    }
+}
+
+pub fn wal_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("wal")
+}
+
+pub fn wal_path(data_dir: &Path, id: u64) -> PathBuf {
+    wal_dir(data_dir).join(format!("{id:06}.log"))
+}
+
+/// Lists the ids of all WAL segments under the data dir, oldest first.
+pub fn list_wal_ids(data_dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(wal_dir(data_dir))? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(stem) = name.strip_suffix(".log") {
+            if let Ok(id) = stem.parse::<u64>() {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Deletes a WAL segment whose contents are now covered by an SSTable.
+pub fn remove_wal(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    fsync_dir(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memtable::Entry;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zynk-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(wal_dir(&dir)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replay_restores_puts_and_deletes() {
+        let dir = temp_dir("replay");
+        let path = wal_path(&dir, 1);
+        {
+            let mut wal = WalWriter::create(path.clone(), 1, WalSyncPolicy::Never).unwrap();
+            wal.append_put(b"a", 1, b"1").unwrap();
+            wal.append_put(b"b", 2, b"2").unwrap();
+            wal.append_delete(b"a", 3).unwrap();
+        }
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 3);
+        assert!(matches!(mem.get(b"a"), Some(Entry::Delete)));
+        assert!(matches!(mem.get(b"b"), Some(Entry::Put(v)) if v == b"2"));
+        assert!(matches!(mem.get_at(b"a", 2), Some(Entry::Put(v)) if v == b"1"));
+        assert_eq!(mem.max_seq(), 3);
+        assert_eq!(list_wal_ids(&dir).unwrap(), vec![1]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replay_stops_at_torn_tail() {
+        let dir = temp_dir("torn");
+        let path = wal_path(&dir, 7);
+        {
+            let mut wal = WalWriter::create(path.clone(), 7, WalSyncPolicy::Always).unwrap();
+            wal.append_put(b"k1", 1, b"v1").unwrap();
+            wal.append_put(b"k2", 2, b"v2").unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 1);
+        assert!(mem.get(b"k1").is_some());
+        assert!(mem.get(b"k2").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_an_error() {
+        let dir = temp_dir("corrupt");
+        let path = wal_path(&dir, 3);
+        {
+            let mut wal = WalWriter::create(path.clone(), 3, WalSyncPolicy::Never).unwrap();
+            wal.append_put(b"k1", 1, b"v1").unwrap();
+            wal.append_put(b"k2", 2, b"v2").unwrap();
+            wal.append_put(b"k3", 3, b"v3").unwrap();
+        }
+        let intact = fs::read(&path).unwrap();
+        let mut bytes = intact.clone();
+        bytes[RECORD_HEADER_SIZE + 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        for last in [true, false] {
+            let err = replay_wal(&path, &MemTable::new(usize::MAX), last).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        assert!(trim_torn_tail(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        // A torn tail is told apart by where it ends, not by what the bytes
+        // after it happen to look like.
+        let mut bytes = intact.clone();
+        let last_record = intact.len() - (RECORD_HEADER_SIZE + OP_HEADER_SIZE + 4);
+        bytes[last_record + RECORD_HEADER_SIZE + 1] ^= 0xff;
+        bytes.resize(bytes.len() + 64, 0);
+        fs::write(&path, &bytes).unwrap();
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 2);
+
+        fs::write(&path, &intact[..intact.len() - 2]).unwrap();
+        let err = replay_wal(&path, &MemTable::new(usize::MAX), false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        trim_torn_tail(&path).unwrap();
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, false).unwrap(), 2);
+        assert!(mem.get(b"k3").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn torn_record_holding_an_encoded_record_is_still_a_tail() {
+        let dir = temp_dir("nested");
+        let inner = wal_path(&dir, 1);
+        {
+            let mut wal = WalWriter::create(inner.clone(), 1, WalSyncPolicy::Never).unwrap();
+            wal.append_put(b"inner", 1, b"value").unwrap();
+        }
+        let path = wal_path(&dir, 2);
+        {
+            let mut wal = WalWriter::create(path.clone(), 2, WalSyncPolicy::Never).unwrap();
+            wal.append_put(b"k", 1, b"v").unwrap();
+            wal.append_put(b"nested", 2, &fs::read(&inner).unwrap())
+                .unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 1);
+        assert!(mem.get(b"inner").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn group_commit_syncs_after_writes_stop() {
+        let dir = temp_dir("group");
+        let path = wal_path(&dir, 1);
+        let policy = WalSyncPolicy::GroupCommit(Duration::from_millis(5));
+        let mut wal = WalWriter::create(path, 1, policy).unwrap();
+        wal.append_put(b"k", 1, b"v").unwrap();
+        let dirty = |wal: &WalWriter| {
+            wal.group
+                .as_ref()
+                .unwrap()
+                .shared
+                .state
+                .lock()
+                .unwrap()
+                .dirty
+        };
+        assert!(dirty(&wal));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while dirty(&wal) {
+            assert!(std::time::Instant::now() < deadline, "never synced");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // A failed background sync fails every later write.
+        let failed = (ErrorKind::Other, "disk gone".to_string());
+        wal.group
+            .as_ref()
+            .unwrap()
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .failed = Some(failed);
+        assert!(wal.append_put(b"k", 2, b"v").is_err());
+        assert!(wal.sync().is_err());
+        drop(wal);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_append_fails_later_writes() {
+        let mut wal = WalWriter::create("/dev/full".into(), 1, WalSyncPolicy::Never).unwrap();
+        let err = wal.append_put(b"k", 1, b"v").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        let err = wal.append_put(b"k", 2, b"v").unwrap_err();
+        assert!(err.to_string().contains("failed earlier"), "{err}");
+    }
+
+    #[test]
+    fn batch_record_replays_whole_or_not_at_all() {
+        let dir = temp_dir("batch");
+        let path = wal_path(&dir, 1);
+        let ops = vec![
+            LoggedOp::Put(b"a".to_vec(), b"1".to_vec()),
+            LoggedOp::Delete(b"b".to_vec()),
+            LoggedOp::Put(b"a".to_vec(), b"2".to_vec()),
+            LoggedOp::DeleteRange(b"c".to_vec(), b"e".to_vec()),
+        ];
+        {
+            let mut wal = WalWriter::create(path.clone(), 1, WalSyncPolicy::Never).unwrap();
+            wal.append_put(b"b", 1, b"0").unwrap();
+            wal.append_put(b"d", 1, b"0").unwrap();
+            wal.append_batch(2, &ops).unwrap();
+        }
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 3);
+        assert_eq!(mem.len(), 5);
+        assert!(matches!(mem.get(b"a"), Some(Entry::Put(v)) if v == b"2"));
+        assert!(matches!(mem.get_at(b"a", 2), Some(Entry::Put(v)) if v == b"1"));
+        assert!(matches!(mem.get(b"b"), Some(Entry::Delete)));
+        assert!(matches!(mem.get(b"d"), Some(Entry::Delete)));
+        assert!(matches!(mem.get_at(b"d", 4), Some(Entry::Put(_))));
+        assert_eq!(mem.max_seq(), 5);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let mem = MemTable::new(usize::MAX);
+        assert_eq!(replay_wal(&path, &mem, true).unwrap(), 2);
+        assert_eq!(mem.len(), 2);
+        assert!(mem.range_tombstones().is_empty());
+        assert!(matches!(mem.get(b"d"), Some(Entry::Put(v)) if v == b"0"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}